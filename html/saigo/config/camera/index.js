@@ -3,10 +3,20 @@
 const device = document.getElementById("device");
 const width = document.getElementById("width");
 const height = document.getElementById("height");
+const frame_rate = document.getElementById("frame_rate");
+const frame_format = document.getElementById("frame_format");
+const controls = document.querySelectorAll("#controls input");
+const auto_controls = document.querySelectorAll("#auto_controls select");
 const handler = throttle(onInput);
 device.addEventListener("input", handler);
 width.addEventListener("input", handler);
 height.addEventListener("input", handler);
+frame_rate.addEventListener("input", handler);
+frame_format.addEventListener("input", handler);
+for (const input of controls)
+	input.addEventListener("input", handler);
+for (const select of auto_controls)
+	select.addEventListener("input", handler);
 
 for (const btn of document.querySelectorAll("#resolution > button"))
 {
@@ -24,17 +34,52 @@ const ctx = canvas.getContext("2d");
 const take_reference_image = document.getElementById("take_reference_image");
 const reference = document.getElementById("reference");
 const referenceCtx = reference.getContext("2d");
+const reference_quality = document.getElementById("reference_quality");
+const adaptive_reference = document.getElementById("adaptive_reference");
+const reset_adapted_reference = document.getElementById("reset_adapted_reference");
+adaptive_reference.addEventListener("input", handler);
+reset_adapted_reference.addEventListener("click", resetAdaptedReference);
 take_reference_image.addEventListener("click", () => getReferenceImage(true));
 
+const estimate_distortion = document.getElementById("estimate_distortion");
+const clear_distortion_points = document.getElementById("clear_distortion_points");
+const reset_distortion = document.getElementById("reset_distortion");
+estimate_distortion.addEventListener("click", estimateDistortion);
+clear_distortion_points.addEventListener("click", () =>
+{
+	distortionPoints = [];
+	render();
+});
+reset_distortion.addEventListener("click", () => setDistortion({ k1: 0, k2: 0, p1: 0, p2: 0 }));
+
+const auto_detect = document.getElementById("auto_detect");
+auto_detect.addEventListener("click", autoDetect);
+
+const camera_status = document.getElementById("camera_status");
+const realign = document.getElementById("realign");
+const auto_realign = document.getElementById("auto_realign");
+realign.addEventListener("click", realignCamera);
+auto_realign.addEventListener("input", handler);
+
 let imageData;
+let config;
 let tl;
 let tr;
 let bl;
 let br;
+let distortionPoints = [];
 
 let draggingCorner = null;
 canvas.addEventListener("mousedown", e =>
 {
+	if (e.shiftKey)
+	{
+		// Shift-click adds a point along the edge of the grid, for estimating lens distortion
+		distortionPoints.push({ x: e.offsetX / canvas.width, y: e.offsetY / canvas.height });
+		render();
+		return;
+	}
+
 	let closestDistance = Infinity;
 	let closestPoint = null;
 	for (const point of [tl, tr, bl, br])
@@ -78,6 +123,9 @@ async function load()
 
 	const ws = new WebSocket(`ws://${location.host}/ws/camera`);
 	ws.addEventListener("message", onMessage);
+
+	const statusWs = new WebSocket(`ws://${location.host}/ws/camera-status`);
+	statusWs.addEventListener("message", onStatusMessage);
 }
 
 async function loadCameras()
@@ -97,10 +145,18 @@ async function loadConfig()
 {
 	const request = new Request("/api/config/camera");
 	const response = await fetch(request);
-	const config = await response.json();
+	config = await response.json();
 	device.value = config.device;
 	width.value = config.width;
 	height.value = config.height;
+	auto_realign.checked = config.auto_realign;
+	adaptive_reference.checked = config.adaptive_reference;
+	frame_rate.value = config.frame_rate;
+	frame_format.value = config.frame_format ?? "";
+	for (const input of controls)
+		input.value = config.controls[input.dataset.control] ?? "";
+	for (const select of auto_controls)
+		select.value = String(config.controls[select.dataset.control] ?? "");
 	tl = config.top_left;
 	tr = config.top_right;
 	bl = config.bottom_left;
@@ -109,16 +165,21 @@ async function loadConfig()
 
 async function onInput()
 {
-	const config =
-	{
-		device: device.value,
-		width: Number(width.value),
-		height: Number(height.value),
-		top_left: tl,
-		top_right: tr,
-		bottom_left: bl,
-		bottom_right: br,
-	};
+	config.device = device.value;
+	config.width = Number(width.value);
+	config.height = Number(height.value);
+	config.auto_realign = auto_realign.checked;
+	config.adaptive_reference = adaptive_reference.checked;
+	config.frame_rate = Number(frame_rate.value);
+	config.frame_format = frame_format.value || null;
+	for (const input of controls)
+		config.controls[input.dataset.control] = input.value === "" ? null : Number(input.value);
+	for (const select of auto_controls)
+		config.controls[select.dataset.control] = select.value === "" ? null : select.value === "true";
+	config.top_left = tl;
+	config.top_right = tr;
+	config.bottom_left = bl;
+	config.bottom_right = br;
 	const request = new Request("/api/config/camera",
 	{
 		method: "PUT",
@@ -139,6 +200,21 @@ async function onMessage(event)
 	render();
 }
 
+function onStatusMessage(event)
+{
+	const status = JSON.parse(event.data);
+	if (!status.streaming)
+	{
+		camera_status.textContent = `The camera is not streaming: ${status.error ?? "waiting for frames"}.`;
+		return;
+	}
+	const drift = status.drift.toFixed(2);
+	const camera = `The camera is streaming at ${status.width}x${status.height} (${status.format}).`;
+	camera_status.textContent = status.moved
+		? `${camera} The board has moved (drift: ${drift} stones).`
+		: `${camera} The board is aligned (drift: ${drift} stones).`;
+}
+
 function render()
 {
 	if (!imageData)
@@ -164,6 +240,118 @@ function render()
 	ctx.beginPath();
 	ctx.ellipse(tr.x * canvas.width, tr.y * canvas.height, 5, 5, 0, 0, 2 * Math.PI);
 	ctx.fill();
+
+	ctx.fillStyle = "#00ffff";
+	for (const point of distortionPoints)
+	{
+		ctx.beginPath();
+		ctx.ellipse(point.x * canvas.width, point.y * canvas.height, 3, 3, 0, 0, 2 * Math.PI);
+		ctx.fill();
+	}
+}
+
+async function estimateDistortion()
+{
+	const request = new Request("/api/config/camera/distortion/estimate",
+	{
+		method: "POST",
+		headers: { "Content-Type": "application/json" },
+		body: JSON.stringify(distortionPoints),
+	});
+	const response = await fetch(request);
+	if (!response.ok)
+	{
+		alert(await response.text());
+		return;
+	}
+	await setDistortion(await response.json());
+}
+
+async function autoDetect()
+{
+	const request = new Request("/api/config/camera/auto-detect",
+	{
+		method: "POST",
+	});
+	const response = await fetch(request);
+	if (!response.ok)
+	{
+		alert(await response.text());
+		return;
+	}
+	const detection = await response.json();
+	const confidence = Math.round(detection.confidence * 100);
+	if (detection.confidence < 0.5 && !confirm(`The grid was detected with low confidence (${confidence}%). Use it anyway?`))
+		return;
+
+	tl = detection.camera.top_left;
+	tr = detection.camera.top_right;
+	bl = detection.camera.bottom_left;
+	br = detection.camera.bottom_right;
+	render();
+	await onInput();
+
+	const { width, height } = detection.board;
+	if (confirm(`Detected a ${width}x${height} board (${confidence}% confidence). Update the board size to match?`))
+	{
+		const request = new Request("/api/config/board",
+		{
+			method: "PUT",
+			headers: { "Content-Type": "application/json" },
+			body: JSON.stringify(detection.board),
+		});
+		const response = await fetch(request);
+		if (!response.ok)
+			alert(await response.text());
+	}
+}
+
+async function realignCamera()
+{
+	const request = new Request("/api/camera/realign",
+	{
+		method: "POST",
+	});
+	const response = await fetch(request);
+	if (!response.ok)
+	{
+		alert(await response.text());
+		return;
+	}
+	const camera = await response.json();
+	config.top_left = tl = camera.top_left;
+	config.top_right = tr = camera.top_right;
+	config.bottom_left = bl = camera.bottom_left;
+	config.bottom_right = br = camera.bottom_right;
+	render();
+}
+
+async function resetAdaptedReference()
+{
+	const request = new Request("/api/config/camera/reference/reset",
+	{
+		method: "POST",
+	});
+	const response = await fetch(request);
+	if (!response.ok)
+		alert(await response.text());
+}
+
+async function setDistortion(distortion)
+{
+	const request = new Request("/api/config/camera/distortion",
+	{
+		method: "PUT",
+		headers: { "Content-Type": "application/json" },
+		body: JSON.stringify(distortion),
+	});
+	const response = await fetch(request);
+	if (!response.ok)
+	{
+		alert(await response.text());
+		return;
+	}
+	config.distortion = distortion;
 }
 
 async function getReferenceImage(take = false)
@@ -174,6 +362,17 @@ async function getReferenceImage(take = false)
 		method: "POST",
 	});
 	const response = await fetch(request);
+	if (!response.ok)
+	{
+		alert(await response.text());
+		return;
+	}
+	const quality = response.headers.get("X-Reference-Quality");
+	if (quality !== null)
+	{
+		const { frames, noise, uniformity } = JSON.parse(quality);
+		reference_quality.textContent = `Combined from ${frames} frames. Noise: ${noise.toFixed(1)}. Brightness uniformity: ${Math.round(uniformity * 100)}%.`;
+	}
 	const image = await createImageBitmap(await response.blob());
 	const w = reference.width = image.width;
 	const h = reference.height = image.height;
@@ -16,6 +16,29 @@ for (const btn of document.querySelectorAll("#board_size > button"))
 	});
 }
 
+const recognizer = document.getElementById("recognizer");
+const model_path = document.getElementById("model_path");
+const fallback_model_path = document.getElementById("fallback_model_path");
+const model_status = document.getElementById("model_status");
+
+document.getElementById("load_model").addEventListener("click", async () =>
+{
+	const request = new Request("/api/model",
+	{
+		method: "PUT",
+		headers: { "Content-Type": "application/json" },
+		body: JSON.stringify({
+			recognizer: recognizer.value,
+			path: model_path.value,
+			fallback_path: fallback_model_path.value || null,
+		}),
+	});
+	const response = await fetch(request);
+	if (!response.ok)
+		alert(await response.text());
+	await loadModel();
+});
+
 const save_profile = document.getElementById("save_profile");
 const load_profile = document.getElementById("load_profile");
 
@@ -75,6 +98,25 @@ async function loadConfig()
 	const config = await response.json();
 	width.value = config.width;
 	height.value = config.height;
+	await loadModel();
+}
+
+async function loadModel()
+{
+	const request = new Request("/api/model");
+	const response = await fetch(request);
+	const status = await response.json();
+	recognizer.value = status.recognizer;
+	model_path.value = status.path;
+	fallback_model_path.value = status.fallback_path ?? "";
+	if (status.error !== null)
+		model_status.textContent = status.error;
+	else if (status.fallback_error !== null)
+		model_status.textContent = status.fallback_error;
+	else if (status.model?.path)
+		model_status.textContent = `Loaded ${status.model.path} (${status.model.parameters} parameters)`;
+	else
+		model_status.textContent = "";
 }
 
 async function loadProfiles()
@@ -4,7 +4,7 @@ const cameraCanvas = document.getElementById("camera");
 const cameraCtx = cameraCanvas.getContext("2d");
 
 const boardCanvas = document.getElementById("board");
-const boardCtx = boardCanvas.getContext("2d");
+const previewCanvas = document.getElementById("preview");
 
 const imageWs = new WebSocket(`ws://${location.host}/ws/board-camera`);
 imageWs.addEventListener("message", onImageMessage);
@@ -15,8 +15,34 @@ rawBoardWs.addEventListener("message", onRawBoardMessage);
 const boardWs = new WebSocket(`ws://${location.host}/ws/board`);
 boardWs.addEventListener("message", onBoardMessage);
 
+const cameraStatusWs = new WebSocket(`ws://${location.host}/ws/camera-status`);
+cameraStatusWs.addEventListener("message", onCameraStatusMessage);
+
+const cameraStatus = document.getElementById("camera_status");
+const realign = document.getElementById("realign");
+realign.addEventListener("click", async () =>
+{
+	const response = await fetch(new Request("/api/camera/realign", { method: "POST" }));
+	if (!response.ok)
+		alert(await response.text());
+});
+
+const empty_threshold = document.getElementById("empty_threshold");
+const black_threshold = document.getElementById("black_threshold");
+const white_threshold = document.getElementById("white_threshold");
+const max_troublesome_points = document.getElementById("max_troublesome_points");
+const apply_recognition = document.getElementById("apply_recognition");
+const preview_status = document.getElementById("preview_status");
+const previewHandler = throttle(updatePreview);
+for (const input of [empty_threshold, black_threshold, white_threshold, max_troublesome_points])
+	input.addEventListener("input", previewHandler);
+apply_recognition.addEventListener("click", applyRecognition);
+
 let imageBitmap = null;
 let data = null;
+let recognitionLoaded = false;
+
+loadRecognition();
 
 async function onImageMessage(event)
 {
@@ -29,11 +55,82 @@ async function onRawBoardMessage(event)
 {
 	data = JSON.parse(event.data);
 	renderCamera();
+	previewHandler();
+}
+
+function onCameraStatusMessage(event)
+{
+	const status = JSON.parse(event.data);
+	const health = status.streaming ? `streaming ${status.width}x${status.height} ${status.format}` : `not streaming (${status.error ?? "no error"})`;
+	cameraStatus.textContent = `Camera ${health}, ${status.moved ? "moved" : "aligned"}, drift ${status.drift.toFixed(2)} stones, ${status.realignments} realignments`;
 }
 
 async function onBoardMessage(event)
 {
-	renderBoard(JSON.parse(event.data));
+	renderBoard(boardCanvas, JSON.parse(event.data));
+}
+
+async function loadRecognition()
+{
+	const response = await fetch(new Request("/api/config/recognition"));
+	const recognition = await response.json();
+	empty_threshold.value = recognition.empty_threshold;
+	black_threshold.value = recognition.black_threshold;
+	white_threshold.value = recognition.white_threshold;
+	max_troublesome_points.value = recognition.max_troublesome_points;
+	recognitionLoaded = true;
+}
+
+function getRecognition()
+{
+	return {
+		empty_threshold: Number(empty_threshold.value),
+		black_threshold: Number(black_threshold.value),
+		white_threshold: Number(white_threshold.value),
+		max_troublesome_points: Number(max_troublesome_points.value),
+	};
+}
+
+async function updatePreview()
+{
+	// Don't preview the defaults of the inputs before the real settings arrive
+	if (!recognitionLoaded)
+		return;
+
+	const request = new Request("/api/config/recognition/preview",
+	{
+		method: "POST",
+		headers: { "Content-Type": "application/json" },
+		body: JSON.stringify(getRecognition()),
+	});
+	const response = await fetch(request);
+	if (!response.ok)
+	{
+		preview_status.textContent = await response.text();
+		return;
+	}
+	const preview = await response.json();
+	const obscured = preview.board.flat().filter(c => c === "?").length;
+	if (obscured === 0)
+		preview_status.textContent = "Preview: the board would be read.";
+	else if (preview.troublesome)
+		preview_status.textContent = `Preview: ${obscured} points would be highlighted as misplaced stones.`;
+	else
+		preview_status.textContent = `Preview: ${obscured} points would be obscured.`;
+	renderBoard(previewCanvas, preview.board);
+}
+
+async function applyRecognition()
+{
+	const request = new Request("/api/config/recognition",
+	{
+		method: "PUT",
+		headers: { "Content-Type": "application/json" },
+		body: JSON.stringify(getRecognition()),
+	});
+	const response = await fetch(request);
+	if (!response.ok)
+		alert(await response.text());
 }
 
 function renderCamera()
@@ -80,8 +177,9 @@ function renderCamera()
 	}
 }
 
-function renderBoard(board)
+function renderBoard(boardCanvas, board)
 {
+	const boardCtx = boardCanvas.getContext("2d");
 	const scale = 47;
 	const offset = scale * 0.5;
 	const w = board[0].length;
@@ -121,6 +219,9 @@ function renderBoard(board)
 				case "W":
 					boardCtx.fillStyle = "white";
 					break;
+				case "?":
+					boardCtx.fillStyle = "red";
+					break;
 				default:
 					continue;
 			}
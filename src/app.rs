@@ -1,45 +1,83 @@
-use std::{mem::take, sync::Arc, time::Duration};
+use std::{
+    mem::take,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use config::{BoardConfig, CameraConfig, Config, DisplayConfig};
+use camera::{CameraStatus, FrameSource, start_camera};
+use config::{
+    BoardConfig, CameraConfig, Config, DisplayConfig, LensDistortion, ModelConfig,
+    Point as ConfigPoint, RecognitionConfig,
+};
+use detect::{BoardDetection, detect_board};
+use filter::TemporalFilter;
 use game::{BoardUpdate, GameState};
+use geometry::{BoardMapping, BoardRemap, board_corners, estimate_distortion};
 use goban::pieces::{goban::Goban, stones::Color, util::coord::Coord};
-use image::{Rgb, Rgb32FImage, RgbImage, Rgba, RgbaImage, buffer::ConvertBuffer};
+use hands::{HandEvent, HandTracker};
+use image::{Rgb32FImage, RgbImage, Rgba, RgbaImage, buffer::ConvertBuffer};
 use imageproc::{
     drawing::{draw_filled_circle_mut, draw_filled_rect_mut, draw_polygon_mut},
-    geometric_transformations::{Interpolation, Projection, warp, warp_into},
+    geometric_transformations::{Interpolation, Projection, warp},
     point::Point,
     rect::Rect,
 };
-use nokhwa::{
-    Camera,
-    pixel_format::RgbFormat,
-    utils::{ApiBackend, CameraFormat, RequestedFormat, RequestedFormatType, Resolution},
-};
+use model::{LoadedModel, ModelStatus};
+use motion::{InferenceStats, MotionGate};
+use offset::estimate_stone_offsets;
 use rand::{Rng, SeedableRng, rngs::StdRng};
-use saigo::{
-    Move, PlayerMove, STONE_SIZE, SerializableColor,
-    vision_model::{VisionModel, read_tensor},
-};
-use tch::{
-    Device, Kind, Tensor,
-    nn::{self, Module},
-};
+use reference::{ReferenceQuality, adapt_reference, combine_frames};
+use saigo::{Move, PlayerMove, STONE_SIZE, SerializableColor, recognizer::VisionModelOutput};
+use serde::{Serialize, Serializer};
 use tokio::{
     sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock, broadcast, watch},
     task::{self, JoinHandle},
-    time::{self, MissedTickBehavior},
+    time::{self, Instant, MissedTickBehavior},
 };
 use tokio_util::sync::CancellationToken;
+use tracking::{Alignment, track_board};
 
 use crate::{
     error::SaigoError,
     sync::{OwnedSender, SenderLock},
 };
 
+pub mod camera;
 pub mod config;
+pub mod detect;
+mod filter;
 pub mod game;
+mod geometry;
+pub mod hands;
+pub mod model;
+pub mod motion;
+mod offset;
+pub mod reference;
+mod tracking;
+
+/// How long to wait before trying to reconnect to an unavailable camera, doubling after each failure.
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// How many frames can fail to be read in a row before the camera is considered disconnected.
+const MAX_FAILED_READS: u32 = 10;
 
-type VisionModelOutput = (f32, f32, f32, f32);
+/// How often to check whether the board has moved.
+const ALIGNMENT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How far the grid can drift before the board is considered to have moved, in pixels of the board image.
+const MAX_DRIFT: f32 = STONE_SIZE as f32 * 0.25;
+
+/// How far a troublesome stone must be from its intersection, in stone widths,
+/// before the display shows which way to move it instead of just highlighting it.
+const MAX_STONE_OFFSET: f32 = 0.2;
+
+/// How many frames to combine when capturing a reference image.
+const REFERENCE_FRAMES: usize = 10;
+
+/// How long to wait for each frame while capturing a reference image.
+const REFERENCE_FRAME_TIMEOUT: Duration = Duration::from_secs(2);
 
 /// The global state of the application.
 pub struct AppState {
@@ -49,54 +87,118 @@ pub struct AppState {
     display_state: Arc<SenderLock<DisplayState>>,
     display_dirty: watch::Sender<()>,
     display_broadcast: watch::Sender<RgbaImage>,
+    /// What the display is projecting, rendered in the space of the board image.
+    board_display_broadcast: watch::Sender<RgbImage>,
     camera_dirty: watch::Sender<()>,
     camera_broadcast: watch::Sender<RgbImage>,
+    /// Signals that the mapping from the camera frame to the board image has changed.
+    board_mapping_dirty: watch::Sender<()>,
     board_camera_broadcast: watch::Sender<RgbImage>,
-    raw_board_broadcast: watch::Sender<Vec<Vec<VisionModelOutput>>>,
+    camera_status_broadcast: watch::Sender<CameraStatus>,
+    raw_board_broadcast: watch::Sender<Vec<Vec<RawPrediction>>>,
+    filtered_board_broadcast: watch::Sender<Vec<Vec<VisionModelOutput>>>,
+    /// Signals that the vision model or the reference image changed, so the whole board has to be recognized again.
+    vision_dirty: watch::Sender<()>,
+    /// How much of the board the vision loop had to recognize.
+    inference_stats: InferenceStats,
     board_broadcast: watch::Sender<Goban>,
     game_broadcast: broadcast::Sender<PlayerMove>,
+    hand_broadcast: broadcast::Sender<HandEvent>,
     cancel: CancellationToken,
     background_tasks: Vec<JoinHandle<()>>,
     pub game: Option<GameState>,
     troublesome_points: Vec<Vec<u8>>,
+    /// How far the stone on each intersection is from the center of the intersection, in stone widths.
+    stone_offsets: Vec<Vec<(f32, f32)>>,
+    /// The quality of the reference image, if it was captured since the server started.
+    reference_quality: Option<ReferenceQuality>,
+    /// The reference image as it has been adapted to the current lighting, if adaptation is enabled.
+    /// It is shared with the vision loop, which only needs to copy it when it changes while the loop still holds it.
+    adapted_reference: Option<Arc<Rgb32FImage>>,
+    /// The vision model, which is shared with the vision loop so that it can be swapped while running.
+    vision_model: Arc<Mutex<Option<LoadedModel>>>,
+    /// The error from loading the configured vision model, if it failed.
+    model_error: Option<String>,
+    /// The vision model used while there is no reference image, if one is configured.
+    fallback_model: Arc<Mutex<Option<LoadedModel>>>,
+    /// The error from loading the configured fallback model, if it failed.
+    fallback_model_error: Option<String>,
+    /// A directory of recorded frames to use instead of the configured camera.
+    replay: Option<PathBuf>,
+    /// A directory to record the raw camera frames to.
+    record: Option<PathBuf>,
 }
 
 impl AppState {
     /// Starts a new instance of the application.
-    pub fn start() -> Arc<RwLock<Self>> {
+    pub fn start(replay: Option<PathBuf>, record: Option<PathBuf>) -> Arc<RwLock<Self>> {
         let config = Config::load(None).expect("Failed to load configuration");
         let width = config.board.width.get();
         let height = config.board.height.get();
         let (display_state, _) = watch::channel(DisplayState::default());
         let (display_dirty, _) = watch::channel(());
         let (display_broadcast, _) = watch::channel(RgbaImage::new(160, 120));
+        let (board_display_broadcast, _) =
+            watch::channel(RgbImage::new(width * STONE_SIZE, height * STONE_SIZE));
         let (camera_dirty, _) = watch::channel(());
         let (camera_broadcast, _) = watch::channel(RgbImage::new(160, 120));
+        let (board_mapping_dirty, _) = watch::channel(());
         let (board_camera_broadcast, _) =
             watch::channel(RgbImage::new(width * STONE_SIZE, height * STONE_SIZE));
-        let (raw_board_broadcast, _) =
+        let (camera_status_broadcast, _) = watch::channel(CameraStatus::default());
+        let (raw_board_broadcast, _) = watch::channel(vec![
+            vec![
+                RawPrediction {
+                    probabilities: (0.0, 0.0, 0.0, 1.0),
+                    offset: (0.0, 0.0),
+                };
+                width as usize
+            ];
+            height as usize
+        ]);
+        let (filtered_board_broadcast, _) =
             watch::channel(vec![
                 vec![(0.0, 0.0, 0.0, 1.0); width as usize];
                 height as usize
             ]);
+        let (vision_dirty, _) = watch::channel(());
         let (board_broadcast, _) = watch::channel(Goban::new((height as u8, width as u8)));
         let (game_broadcast, _) = broadcast::channel(4);
+        let (hand_broadcast, _) = broadcast::channel(16);
+        let (vision_model, model_error) = load_model(&config.model);
+        let (fallback_model, fallback_model_error) = load_fallback_model(&config.model);
         let state = Self {
             config,
             board_config_lock: Arc::new(RwLock::new(())),
             display_state: Arc::new(SenderLock::new(display_state)),
             display_dirty,
             display_broadcast,
+            board_display_broadcast,
             camera_dirty,
             camera_broadcast,
+            board_mapping_dirty,
             board_camera_broadcast,
+            camera_status_broadcast,
             raw_board_broadcast,
+            filtered_board_broadcast,
+            vision_dirty,
+            inference_stats: InferenceStats::default(),
             board_broadcast,
             game_broadcast,
+            hand_broadcast,
             cancel: CancellationToken::default(),
             background_tasks: vec![],
             game: None,
             troublesome_points: vec![vec![0u8; width as usize]; height as usize],
+            stone_offsets: vec![vec![(0.0, 0.0); width as usize]; height as usize],
+            reference_quality: None,
+            adapted_reference: None,
+            vision_model: Arc::new(Mutex::new(vision_model)),
+            model_error,
+            fallback_model: Arc::new(Mutex::new(fallback_model)),
+            fallback_model_error,
+            replay,
+            record,
         };
         let state_ref = Arc::new(RwLock::new(state));
         Self::start_background_tasks(&state_ref);
@@ -142,6 +244,11 @@ impl AppState {
         self.display_broadcast.subscribe()
     }
 
+    /// Returns a new receiver for the board display broadcast channel.
+    pub fn subscribe_to_board_display_broadcast(&self) -> watch::Receiver<RgbImage> {
+        self.board_display_broadcast.subscribe()
+    }
+
     /// Returns a new receiver for the camera broadcast channel.
     pub fn subscribe_to_camera_broadcast(&self) -> watch::Receiver<RgbImage> {
         self.camera_broadcast.subscribe()
@@ -152,8 +259,13 @@ impl AppState {
         self.board_camera_broadcast.subscribe()
     }
 
+    /// Returns a new receiver for the camera status broadcast channel.
+    pub fn subscribe_to_camera_status_broadcast(&self) -> watch::Receiver<CameraStatus> {
+        self.camera_status_broadcast.subscribe()
+    }
+
     /// Returns a new receiver for the raw board broadcast channel.
-    pub fn subscribe_to_raw_board_broadcast(&self) -> watch::Receiver<Vec<Vec<VisionModelOutput>>> {
+    pub fn subscribe_to_raw_board_broadcast(&self) -> watch::Receiver<Vec<Vec<RawPrediction>>> {
         self.raw_board_broadcast.subscribe()
     }
 
@@ -167,6 +279,11 @@ impl AppState {
         self.game_broadcast.subscribe()
     }
 
+    /// Returns a new receiver for the hand broadcast channel.
+    pub fn subscribe_to_hand_broadcast(&self) -> broadcast::Receiver<HandEvent> {
+        self.hand_broadcast.subscribe()
+    }
+
     /// Saves the current configuration to the specified profile.
     pub fn save_config(&self, profile: &str) -> Result<(), SaigoError> {
         self.config.save(Some(profile), false)
@@ -179,10 +296,29 @@ impl AppState {
     ) -> Result<(), SaigoError> {
         let _guard = Self::write_board_config(state_ref).await?;
         let mut state = state_ref.write().await;
-        state.config = Config::load(Some(profile))?;
+        let config = Config::load(Some(profile))?;
+        if config.model.path != state.config.model.path
+            || config.model.recognizer != state.config.model.recognizer
+        {
+            let (vision_model, model_error) = task::block_in_place(|| load_model(&config.model));
+            *state.vision_model.lock().unwrap() = vision_model;
+            state.model_error = model_error;
+            state.vision_dirty.send_replace(());
+        }
+        if config.model.fallback_path != state.config.model.fallback_path {
+            let (fallback_model, fallback_model_error) =
+                task::block_in_place(|| load_fallback_model(&config.model));
+            *state.fallback_model.lock().unwrap() = fallback_model;
+            state.fallback_model_error = fallback_model_error;
+            state.vision_dirty.send_replace(());
+        }
+        state.config = config;
         state.config.save(None, false)?;
+        state.reference_quality = None;
+        state.adapted_reference = None;
         state.display_dirty.send_replace(());
         state.camera_dirty.send_replace(());
+        state.board_mapping_dirty.send_replace(());
         state.on_board_config_changed();
         Ok(())
     }
@@ -201,6 +337,8 @@ impl AppState {
         let mut state = state_ref.write().await;
         if state.config.board.width != board.width || state.config.board.height != board.height {
             state.config.camera.reference_image = None;
+            state.reference_quality = None;
+            state.adapted_reference = None;
         }
         state.config.board = board;
         state.config.save(None, false)?;
@@ -237,6 +375,10 @@ impl AppState {
             vec![0u8; self.config.board.width.get() as usize];
             self.config.board.height.get() as usize
         ];
+        self.stone_offsets = vec![
+            vec![(0.0, 0.0); self.config.board.width.get() as usize];
+            self.config.board.height.get() as usize
+        ];
     }
 
     /// Gets the current display configuration.
@@ -251,6 +393,87 @@ impl AppState {
         self.config.save_fast()
     }
 
+    /// Gets the configured vision model and whether it could be loaded.
+    pub fn get_model_status(&self) -> ModelStatus {
+        ModelStatus {
+            recognizer: self.config.model.recognizer,
+            path: self.config.model.path.clone(),
+            model: self
+                .vision_model
+                .lock()
+                .unwrap()
+                .as_ref()
+                .map(|model| model.info.clone()),
+            error: self.model_error.clone(),
+            fallback_path: self.config.model.fallback_path.clone(),
+            fallback_model: self
+                .fallback_model
+                .lock()
+                .unwrap()
+                .as_ref()
+                .map(|model| model.info.clone()),
+            fallback_error: self.fallback_model_error.clone(),
+            inference: self.inference_stats,
+        }
+    }
+
+    /// Loads a new vision model and fallback model and switches to them, keeping the current models if either is invalid.
+    pub async fn set_model(
+        state_ref: &Arc<RwLock<Self>>,
+        model: ModelConfig,
+    ) -> Result<(), SaigoError> {
+        let vision_model = task::block_in_place(|| LoadedModel::load(&model))?;
+        let fallback_model = match &model.fallback_path {
+            Some(path) => Some(task::block_in_place(|| LoadedModel::load_fallback(path))?),
+            None => None,
+        };
+        let mut state = state_ref.write().await;
+        *state.vision_model.lock().unwrap() = Some(vision_model);
+        state.model_error = None;
+        *state.fallback_model.lock().unwrap() = fallback_model;
+        state.fallback_model_error = None;
+        state.vision_dirty.send_replace(());
+        state.config.model = model;
+        state.config.save_fast()
+    }
+
+    /// Gets the current recognition configuration.
+    pub fn get_recognition_config(&self) -> &RecognitionConfig {
+        &self.config.recognition
+    }
+
+    /// Sets the recognition configuration.
+    pub fn set_recognition_config(
+        &mut self,
+        recognition: RecognitionConfig,
+    ) -> Result<(), SaigoError> {
+        self.config.recognition = recognition;
+        self.config.save_fast()
+    }
+
+    /// Reads the latest vision model output using the given recognition settings, without applying them.
+    pub fn preview_recognition(&self, recognition: &RecognitionConfig) -> RecognitionPreview {
+        let probabilities = self.filtered_board_broadcast.borrow();
+        let board = probabilities
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|p| match classify_intersection(p, recognition) {
+                        Some(None) => ' ',
+                        Some(Some(Color::Black)) => 'B',
+                        Some(Some(Color::White)) => 'W',
+                        None => '?',
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let obscured = board.iter().flatten().filter(|&&c| c == '?').count();
+        RecognitionPreview {
+            board,
+            troublesome: obscured > 0 && obscured <= recognition.max_troublesome_points,
+        }
+    }
+
     /// Gets the current camera configuration.
     pub fn get_camera_config(&self) -> &CameraConfig {
         &self.config.camera
@@ -258,15 +481,30 @@ impl AppState {
 
     /// Sets the camera configuration.
     pub fn set_camera_config(&mut self, mut camera: CameraConfig) -> Result<(), SaigoError> {
+        if camera.frame_rate == 0 {
+            return Err(SaigoError::InvalidCameraConfig(
+                "The frame rate must be at least 1.".to_string(),
+            ));
+        }
+
         // Transfer the old reference image because it's not included in the serialized config
         camera.reference_image = self.config.camera.reference_image.take();
 
         // If the camera settings change, reset the camera
         let should_reset = self.config.camera.device != camera.device
             || self.config.camera.width != camera.width
-            || self.config.camera.height != camera.height;
+            || self.config.camera.height != camera.height
+            || self.config.camera.frame_rate != camera.frame_rate
+            || self.config.camera.frame_format != camera.frame_format
+            || self.config.camera.controls != camera.controls;
+
+        // Go back to the captured reference image if adaptation is turned off
+        if !camera.adaptive_reference {
+            self.adapted_reference = None;
+        }
 
         self.config.camera = camera;
+        self.board_mapping_dirty.send_replace(());
 
         if should_reset {
             self.camera_dirty.send_replace(());
@@ -275,10 +513,158 @@ impl AppState {
         self.config.save_fast()
     }
 
-    /// Captures a reference image of the board.
-    pub fn take_reference_image(&mut self) -> Result<(), SaigoError> {
-        self.config.camera.reference_image = Some(self.board_camera_broadcast.borrow().clone());
-        self.config.save_reference_image(None)
+    /// Sets the lens distortion coefficients.
+    pub fn set_lens_distortion(&mut self, distortion: LensDistortion) -> Result<(), SaigoError> {
+        self.config.camera.distortion = distortion;
+        self.board_mapping_dirty.send_replace(());
+        self.config.save_fast()
+    }
+
+    /// Estimates the lens distortion from points along the edges of the grid in the current camera frame.
+    pub fn estimate_lens_distortion(
+        &self,
+        points: &[ConfigPoint],
+    ) -> Result<LensDistortion, SaigoError> {
+        if points.len() < 4 {
+            return Err(SaigoError::InvalidCalibration(
+                "At least 4 points along the edges of the grid are required.".to_string(),
+            ));
+        }
+        let (width, height) = self.camera_broadcast.borrow().dimensions();
+        estimate_distortion(&self.config.camera, points, width, height).ok_or_else(|| {
+            SaigoError::InvalidCalibration(
+                "The points don't contain enough information to estimate the distortion."
+                    .to_string(),
+            )
+        })
+    }
+
+    /// Detects the board's grid in the current camera frame and proposes new corner points and a board size.
+    pub fn detect_board(&self) -> Result<BoardDetection, SaigoError> {
+        let frame = self.camera_broadcast.borrow().clone();
+        detect_board(&frame, &self.config.camera).ok_or_else(|| {
+            SaigoError::InvalidCalibration(
+                "Couldn't find a regular grid in the camera frame.".to_string(),
+            )
+        })
+    }
+
+    /// Gets the current status of the camera.
+    pub fn get_camera_status(&self) -> CameraStatus {
+        self.camera_status_broadcast.borrow().clone()
+    }
+
+    /// Corrects the corner points to follow the board if it has moved since they were set.
+    /// Small movements are tracked against the reference image,
+    /// and larger ones fall back to detecting the grid from scratch.
+    pub fn realign_camera(&mut self) -> Result<(), SaigoError> {
+        let frame = self.camera_broadcast.borrow().clone();
+        let (width, height) = frame.dimensions();
+        let corners = match self.track_alignment(&self.to_board_image(&frame)) {
+            Some(alignment) => {
+                let mapping =
+                    BoardMapping::new(&self.config.camera, &self.config.board, width, height);
+                // Move each corner to where its intersection appears now
+                board_corners(&self.config.board).map(|corner| {
+                    let (x, y) = alignment.correction * corner;
+                    let (x, y) = mapping.board_to_frame(x, y);
+                    ConfigPoint {
+                        x: x / width as f32,
+                        y: y / height as f32,
+                    }
+                })
+            }
+            None => {
+                let detection = detect_board(&frame, &self.config.camera)
+                    .filter(|detection| {
+                        detection.board.width == self.config.board.width
+                            && detection.board.height == self.config.board.height
+                            && detection.confidence >= 0.5
+                    })
+                    .ok_or_else(|| {
+                        SaigoError::InvalidCalibration(
+                            "Couldn't find the board in the camera frame.".to_string(),
+                        )
+                    })?;
+                let camera = detection.camera;
+                [
+                    camera.top_left,
+                    camera.top_right,
+                    camera.bottom_left,
+                    camera.bottom_right,
+                ]
+            }
+        };
+
+        let [top_left, top_right, bottom_left, bottom_right] = corners;
+        self.config.camera.top_left = top_left;
+        self.config.camera.top_right = top_right;
+        self.config.camera.bottom_left = bottom_left;
+        self.config.camera.bottom_right = bottom_right;
+        self.board_mapping_dirty.send_replace(());
+        self.camera_status_broadcast.send_modify(|status| {
+            status.moved = false;
+            status.drift = 0.0;
+            status.realignments += 1;
+        });
+        self.config.save_fast()
+    }
+
+    /// Tracks how the grid in the board image has moved relative to the reference image.
+    fn track_alignment(&self, board_frame: &RgbImage) -> Option<Alignment> {
+        let reference = self.config.camera.reference_image.as_ref()?;
+        track_board(reference, board_frame, &self.config.board)
+    }
+
+    /// Captures a reference image of the board by combining several consecutive frames.
+    pub async fn take_reference_image(state_ref: &Arc<RwLock<Self>>) -> Result<(), SaigoError> {
+        let mut board_camera_receiver = state_ref.read().await.board_camera_broadcast.subscribe();
+        let mut frames = Vec::with_capacity(REFERENCE_FRAMES);
+        while frames.len() < REFERENCE_FRAMES {
+            match time::timeout(REFERENCE_FRAME_TIMEOUT, board_camera_receiver.changed()).await {
+                Ok(Ok(())) => frames.push(board_camera_receiver.borrow_and_update().clone()),
+                _ => {
+                    return Err(SaigoError::CameraUnavailable(
+                        "The camera stopped sending frames while capturing the reference image."
+                            .to_string(),
+                    ));
+                }
+            }
+        }
+
+        let (reference_image, quality) = task::block_in_place(|| combine_frames(&frames))?;
+        let mut state = state_ref.write().await;
+        state.config.camera.reference_image = Some(reference_image);
+        state.reference_quality = Some(quality);
+        state.adapted_reference = None;
+        state.vision_dirty.send_replace(());
+        state.config.save_reference_image(None)
+    }
+
+    /// Discards the changes made to the reference image to follow the lighting, going back to the captured image.
+    pub fn reset_adapted_reference(&mut self) {
+        self.adapted_reference = None;
+        self.vision_dirty.send_replace(());
+    }
+
+    /// Blends the current board image into the adapted reference image where the board is confidently empty.
+    fn update_adapted_reference(
+        &mut self,
+        image: &Rgb32FImage,
+        probabilities: &[Vec<VisionModelOutput>],
+    ) {
+        let Some(reference_image) = &self.config.camera.reference_image else {
+            return;
+        };
+        let reference = self
+            .adapted_reference
+            .get_or_insert_with(|| Arc::new(reference_image.convert()));
+        adapt_reference(Arc::make_mut(reference), image, probabilities);
+    }
+
+    /// Gets the quality of the reference image, if it was captured since the server started.
+    pub fn get_reference_quality(&self) -> Option<&ReferenceQuality> {
+        self.reference_quality.as_ref()
     }
 
     /// Tries to take control of the display state.
@@ -288,61 +674,17 @@ impl AppState {
 
     /// Transforms the camera image to the normalized board image.
     fn to_board_image(&self, frame: &RgbImage) -> RgbImage {
-        // Buffer to copy the board image to
-        let mut board_image = RgbImage::new(
-            self.config.board.width.get() * STONE_SIZE,
-            self.config.board.height.get() * STONE_SIZE,
-        );
+        self.board_remap(frame.width(), frame.height()).apply(frame)
+    }
 
-        // Transform the image coordinates to between 0 and 1, since that's how the control points are represented
-        let normalize_transform =
-            Projection::scale(1.0 / frame.width() as f32, 1.0 / frame.height() as f32);
-
-        // Transform from the 0-1 coordinate system to the final board image
-        let perspective_transform = Projection::from_control_points(
-            [
-                (self.config.camera.top_left.x, self.config.camera.top_left.y),
-                (
-                    self.config.camera.top_right.x,
-                    self.config.camera.top_right.y,
-                ),
-                (
-                    self.config.camera.bottom_left.x,
-                    self.config.camera.bottom_left.y,
-                ),
-                (
-                    self.config.camera.bottom_right.x,
-                    self.config.camera.bottom_right.y,
-                ),
-            ],
-            [
-                (STONE_SIZE as f32 * 0.5, STONE_SIZE as f32 * 0.5),
-                (
-                    STONE_SIZE as f32 * (self.config.board.width.get() as f32 - 0.5),
-                    STONE_SIZE as f32 * 0.5,
-                ),
-                (
-                    STONE_SIZE as f32 * 0.5,
-                    STONE_SIZE as f32 * (self.config.board.height.get() as f32 - 0.5),
-                ),
-                (
-                    STONE_SIZE as f32 * (self.config.board.width.get() as f32 - 0.5),
-                    STONE_SIZE as f32 * (self.config.board.height.get() as f32 - 0.5),
-                ),
-            ],
+    /// Builds the lookup table that transforms camera frames of the given size to the normalized board image.
+    fn board_remap(&self, frame_width: u32, frame_height: u32) -> BoardRemap {
+        BoardRemap::new(
+            &self.config.camera,
+            &self.config.board,
+            frame_width,
+            frame_height,
         )
-        .unwrap_or(normalize_transform.invert());
-
-        // Warp the camera frame to the board image
-        warp_into(
-            frame,
-            &normalize_transform.and_then(perspective_transform),
-            Interpolation::Bilinear,
-            Rgb([0, 0, 0]),
-            &mut board_image,
-        );
-
-        board_image
     }
 
     /// Starts a new game.
@@ -361,11 +703,13 @@ impl AppState {
     ) -> JoinHandle<()> {
         tokio::spawn(async move {
             let broadcast;
+            let board_display_broadcast;
             let mut display_state_receiver;
             let mut display_dirty_receiver;
             {
                 let state = state_ref.read().await;
                 broadcast = state.display_broadcast.clone();
+                board_display_broadcast = state.board_display_broadcast.clone();
                 // Subscribe to state updates
                 display_state_receiver = state.display_state.subscribe();
                 display_state_receiver.mark_changed();
@@ -396,6 +740,8 @@ impl AppState {
                         let display_state = *display_state_receiver.borrow();
                         let state = state_ref.read().await;
                         broadcast.send_replace(state.render(display_state, even_tick));
+                        board_display_broadcast
+                            .send_replace(state.render_board_display(display_state, even_tick));
                     }
                     Err(_) => {
                         // If the channel is closed, stop rendering
@@ -414,19 +760,34 @@ impl AppState {
         tokio::spawn(async move {
             let camera_broadcast;
             let board_camera_broadcast;
+            let camera_status_broadcast;
             let mut dirty_receiver;
+            let mut board_mapping_dirty_receiver;
+            let record;
             {
                 let state = state_ref.read().await;
                 camera_broadcast = state.camera_broadcast.clone();
                 board_camera_broadcast = state.board_camera_broadcast.clone();
+                camera_status_broadcast = state.camera_status_broadcast.clone();
                 // Subscribe to camera configuration changes that require a reset
                 dirty_receiver = state.camera_dirty.subscribe();
                 dirty_receiver.mark_changed();
+                // Subscribe to configuration changes that move the board within the frame
+                board_mapping_dirty_receiver = state.board_mapping_dirty.subscribe();
+                record = state.record.clone();
             }
 
-            let mut camera: Option<Camera> = None;
-
-            // Limit the frame rate to 10 FPS
+            let mut camera: Option<Box<dyn FrameSource>> = None;
+            let mut board_remap: Option<BoardRemap> = None;
+            let mut reconnect_at = Instant::now();
+            let mut reconnect_delay = MIN_RECONNECT_DELAY;
+            let mut failed_reads = 0;
+            let mut record_index = 0;
+            let mut last_alignment_check = Instant::now();
+            let mut drifted_checks = 0;
+            let mut lost_checks = 0;
+
+            // Limit the frame rate to the configured rate, which is updated when the camera is reset
             let mut interval = time::interval(Duration::from_millis(100));
             interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
             while !cancel.is_cancelled() {
@@ -440,17 +801,142 @@ impl AppState {
                 // If the camera capture settings change, reset the camera
                 if dirty_receiver.has_changed().unwrap() {
                     dirty_receiver.mark_unchanged();
-                    camera = start_camera(&state_ref.read().await.config.camera);
+                    camera = None;
+                    reconnect_at = Instant::now();
+                    reconnect_delay = MIN_RECONNECT_DELAY;
+
+                    let frame_rate = state_ref.read().await.config.camera.frame_rate.max(1);
+                    interval = time::interval(Duration::from_secs(1) / frame_rate);
+                    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                }
+
+                // Try to (re)connect to the camera, backing off while it is unavailable
+                if camera.is_none() && Instant::now() >= reconnect_at {
+                    let state = state_ref.read().await;
+                    match start_camera(&state.config.camera, state.replay.as_deref()) {
+                        Ok(source) => {
+                            CameraStatus::update(&camera_status_broadcast, |status| {
+                                status.connected = true;
+                                status.format = Some(source.format());
+                                status.error = None;
+                            });
+                            camera = Some(source);
+                            reconnect_delay = MIN_RECONNECT_DELAY;
+                            failed_reads = 0;
+                        }
+                        Err(e) => {
+                            CameraStatus::update(&camera_status_broadcast, |status| {
+                                status.connected = false;
+                                status.streaming = false;
+                                status.format = None;
+                                status.error = Some(e.to_string());
+                            });
+                            reconnect_at = Instant::now() + reconnect_delay;
+                            reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+                        }
+                    }
                 }
+                let Some(source) = &mut camera else {
+                    continue;
+                };
 
-                // Try to capture a frame
-                if let Some(frame) = read_frame(camera.as_mut()) {
+                // Try to capture a frame, which blocks until the camera or stream produces one
+                let frame = match task::block_in_place(|| source.read_frame()) {
+                    Ok(frame) => {
+                        failed_reads = 0;
+                        CameraStatus::update(&camera_status_broadcast, |status| {
+                            status.frame_received(&frame)
+                        });
+                        frame
+                    }
+                    Err(e) => {
+                        // If the camera keeps failing, assume it was disconnected and start reconnecting
+                        failed_reads += 1;
+                        let disconnected = failed_reads >= MAX_FAILED_READS;
+                        if disconnected {
+                            camera = None;
+                            reconnect_at = Instant::now() + reconnect_delay;
+                        }
+                        CameraStatus::update(&camera_status_broadcast, |status| {
+                            status.connected = !disconnected;
+                            status.streaming = false;
+                            status.error = Some(e.to_string());
+                        });
+                        continue;
+                    }
+                };
+
+                // Record the raw frame if requested
+                if let Some(record) = &record {
+                    if let Err(e) = frame.save(record.join(format!("{}.png", record_index))) {
+                        println!("Failed to record frame: {}", e);
+                    }
+                    record_index += 1;
+                }
+
+                // Rebuild the lookup table if the configuration or the frame size changed
+                if board_mapping_dirty_receiver.has_changed().unwrap()
+                    || !board_remap.as_ref().is_some_and(|remap| remap.fits(&frame))
+                {
+                    board_mapping_dirty_receiver.mark_unchanged();
                     let state = state_ref.read().await;
-                    let board_frame = state.to_board_image(&frame);
-                    // Broadcast the raw frame
-                    camera_broadcast.send_replace(frame);
-                    // Broadcast the board frame
-                    board_camera_broadcast.send_replace(board_frame);
+                    board_remap = Some(task::block_in_place(|| {
+                        state.board_remap(frame.width(), frame.height())
+                    }));
+                }
+                let Some(remap) = &board_remap else {
+                    continue;
+                };
+                // Warp the frame outside the lock, since it's the most expensive part of each frame
+                let board_frame = remap.apply(&frame);
+
+                let state = state_ref.read().await;
+
+                // Periodically check whether the board has moved relative to the camera
+                let check_alignment = last_alignment_check.elapsed() >= ALIGNMENT_CHECK_INTERVAL
+                    && state.config.camera.reference_image.is_some();
+                let alignment = check_alignment.then(|| {
+                    last_alignment_check = Instant::now();
+                    task::block_in_place(|| state.track_alignment(&board_frame))
+                });
+                let auto_realign = state.config.camera.auto_realign;
+                drop(state);
+
+                // Broadcast the raw frame
+                camera_broadcast.send_replace(frame);
+                // Broadcast the board frame
+                board_camera_broadcast.send_replace(board_frame);
+
+                if let Some(alignment) = alignment {
+                    match &alignment {
+                        Some(alignment) if alignment.drift > MAX_DRIFT => {
+                            drifted_checks += 1;
+                            lost_checks = 0;
+                        }
+                        Some(_) => {
+                            drifted_checks = 0;
+                            lost_checks = 0;
+                        }
+                        None => lost_checks += 1,
+                    }
+
+                    // Require several checks in a row, so that briefly reaching over the board doesn't count
+                    let moved = drifted_checks >= 2 || lost_checks >= 10;
+                    CameraStatus::update(&camera_status_broadcast, |status| {
+                        status.moved = moved;
+                        if let Some(alignment) = &alignment {
+                            status.drift = alignment.drift / STONE_SIZE as f32;
+                        }
+                    });
+
+                    if moved && auto_realign {
+                        let mut state = state_ref.write().await;
+                        // If it fails, the board stays marked as moved and it is retried at the next check
+                        if task::block_in_place(|| state.realign_camera()).is_ok() {
+                            drifted_checks = 0;
+                            lost_checks = 0;
+                        }
+                    }
                 }
             }
         })
@@ -462,23 +948,45 @@ impl AppState {
         cancel: CancellationToken,
     ) -> JoinHandle<()> {
         tokio::spawn(async move {
-            let device = Device::cuda_if_available();
-            let mut vs = nn::VarStore::new(device);
-            let model = VisionModel::new(vs.root());
-            vs.load("model.safetensors").unwrap();
-
+            let vision_model;
+            let fallback_model;
             let raw_board_broadcast;
+            let filtered_board_broadcast;
             let board_broadcast;
+            let hand_broadcast;
             let mut board_camera_receiver;
+            let mut board_display_receiver;
+            let mut vision_dirty_receiver;
+            let mut board_mapping_dirty_receiver;
             {
                 let state = state_ref.read().await;
+                vision_model = state.vision_model.clone();
+                fallback_model = state.fallback_model.clone();
                 raw_board_broadcast = state.raw_board_broadcast.clone();
+                filtered_board_broadcast = state.filtered_board_broadcast.clone();
                 board_broadcast = state.board_broadcast.clone();
+                hand_broadcast = state.hand_broadcast.clone();
                 board_camera_receiver = state.board_camera_broadcast.subscribe();
+                board_display_receiver = state.board_display_broadcast.subscribe();
+                board_display_receiver.mark_changed();
+                // Subscribe to changes that make the previous output of the model out of date
+                vision_dirty_receiver = state.vision_dirty.subscribe();
+                board_mapping_dirty_receiver = state.board_mapping_dirty.subscribe();
             }
 
             let mut current_board = board_broadcast.borrow().clone();
             let mut proposed_update = Err(vec![]);
+            let mut temporal_filter = TemporalFilter::new();
+            let mut motion_gate = MotionGate::new();
+            let mut hand_tracker = HandTracker::new();
+            // The reference image and the display rarely change, so they are only converted when they do
+            let mut converted_reference: Option<(RgbImage, Arc<Rgb32FImage>)> = None;
+            let mut display = Rgb32FImage::new(0, 0);
+            // Whether the last frame was recognized without a reference image, possibly by a different model
+            let mut without_reference = false;
+            // The last error from the recognizer, so that a broken model isn't reported every frame
+            let mut last_error: Option<String> = None;
+            let mut stone_offsets: Vec<Vec<(f32, f32)>> = vec![];
 
             while let Ok(()) = board_camera_receiver.changed().await {
                 if cancel.is_cancelled() {
@@ -486,21 +994,147 @@ impl AppState {
                 }
                 // Run the neural network on the camera image
                 let reference;
+                let adaptive_reference;
+                let recognition;
                 let mut troublesome_points;
                 {
                     let state = state_ref.read().await;
-                    reference = match &state.config.camera.reference_image {
-                        Some(img) => img.convert(),
-                        None => continue,
+                    // Don't trust the board image while the camera is out of alignment
+                    if state.camera_status_broadcast.borrow().moved {
+                        // Mark the frame as seen, or the loop would spin until the camera is realigned
+                        board_camera_receiver.mark_unchanged();
+                        continue;
+                    }
+                    reference = match (
+                        &state.adapted_reference,
+                        &state.config.camera.reference_image,
+                    ) {
+                        (Some(img), _) => Some(img.clone()),
+                        (None, Some(img)) => match &converted_reference {
+                            Some((source, converted)) if source == img => Some(converted.clone()),
+                            _ => {
+                                let converted = Arc::new(img.convert());
+                                converted_reference = Some((img.clone(), converted.clone()));
+                                Some(converted)
+                            }
+                        },
+                        // Without a reference image, only a model that doesn't read one can be used
+                        (None, None) => None,
                     };
+                    adaptive_reference = state.config.camera.adaptive_reference;
+                    recognition = state.config.recognition.clone();
                     troublesome_points = state.troublesome_points.clone();
                 }
-                let img = board_camera_receiver.borrow_and_update().convert();
-                let result =
-                    task::block_in_place(|| run_vision_model(&model, img, reference, device));
-                let board = get_board(&result);
+                let img: Rgb32FImage = board_camera_receiver.borrow_and_update().convert();
+                // Tell the model what the display is projecting, so that the light isn't mistaken for stones
+                if board_display_receiver.has_changed().unwrap_or(false) {
+                    display = board_display_receiver.borrow_and_update().convert();
+                }
+                if display.dimensions() != img.dimensions() {
+                    // The display hasn't been rendered for the current board size yet
+                    display = Rgb32FImage::new(img.width(), img.height());
+                }
+                if vision_dirty_receiver.has_changed().unwrap_or(false)
+                    || board_mapping_dirty_receiver.has_changed().unwrap_or(false)
+                    || without_reference != reference.is_none()
+                {
+                    vision_dirty_receiver.mark_unchanged();
+                    board_mapping_dirty_receiver.mark_unchanged();
+                    motion_gate.invalidate();
+                }
+                // Only run the model on the parts of the board that changed since the last frame
+                without_reference = reference.is_none();
+                let result = task::block_in_place(|| {
+                    let model = vision_model.lock().unwrap();
+                    let fallback = fallback_model.lock().unwrap();
+                    match &reference {
+                        Some(reference) => Some(motion_gate.recognize(
+                            model.as_ref()?.recognizer.as_ref(),
+                            &img,
+                            reference,
+                            &display,
+                        )),
+                        None => {
+                            // Prefer the configured model if it can do without the reference image
+                            let model = model
+                                .as_ref()
+                                .filter(|model| !model.recognizer.needs_reference())
+                                .or(fallback.as_ref())?;
+                            let blank = Rgb32FImage::new(img.width(), img.height());
+                            Some(motion_gate.recognize(
+                                model.recognizer.as_ref(),
+                                &img,
+                                &blank,
+                                &display,
+                            ))
+                        }
+                    }
+                });
+                // Without a valid model, there's nothing to do until one is loaded or a reference image is taken
+                let result = match result {
+                    None => continue,
+                    Some(Err(e)) => {
+                        // Skip the frame, since there's no output to decide on
+                        if last_error.as_ref() != Some(&e) {
+                            println!("Failed to recognize the board: {}", e);
+                            last_error = Some(e);
+                        }
+                        continue;
+                    }
+                    Some(Ok(result)) => {
+                        last_error = None;
+                        result
+                    }
+                };
+                // Estimate how far each stone is from its intersection, so the user can be shown which way to move it
+                stone_offsets = match &reference {
+                    Some(reference) => task::block_in_place(|| {
+                        estimate_stone_offsets(&img, reference, &display, &result, &stone_offsets)
+                    }),
+                    // Stones can't be told apart from the board without a reference image to compare to
+                    None => result
+                        .iter()
+                        .map(|row| vec![(0.0, 0.0); row.len()])
+                        .collect(),
+                };
+
+                // Smooth out flickering between frames before deciding on the state of the board
+                let filtered = temporal_filter.update(&result);
+                let board = get_board(&filtered, &recognition);
+
+                // Follow hands reaching over the board, so that clients can show the user is placing a stone
+                let obscured = match &board {
+                    Ok(_) => vec![],
+                    Err(obscured_coords) => obscured_coords.clone(),
+                };
+                for event in hand_tracker.update(&obscured) {
+                    let _ = hand_broadcast.send(event);
+                }
+
+                // Let go of the reference image, so that it can be adapted without copying it
+                drop(reference);
+                // Let the reference image follow the lighting where the board is empty
+                if adaptive_reference {
+                    let mut state = state_ref.write().await;
+                    task::block_in_place(|| state.update_adapted_reference(&img, &filtered));
+                }
+
                 // Broadcast the raw output of the neural network
-                raw_board_broadcast.send_replace(result);
+                let raw_board = result
+                    .iter()
+                    .zip(&stone_offsets)
+                    .map(|(row, offsets)| {
+                        row.iter()
+                            .zip(offsets)
+                            .map(|(&probabilities, &offset)| RawPrediction {
+                                probabilities,
+                                offset,
+                            })
+                            .collect()
+                    })
+                    .collect();
+                raw_board_broadcast.send_replace(raw_board);
+                filtered_board_broadcast.send_replace(filtered);
                 match board {
                     Ok(board) => {
                         // If the board has changed, broadcast it
@@ -509,12 +1143,15 @@ impl AppState {
                             board_broadcast.send_replace(board);
                             // If the updated board results in a valid change to the state of the game,
                             // wait the specified cooldown before applying it
+                            let hand_left = hand_tracker.hand_left_recently();
                             proposed_update = state_ref
                                 .read()
                                 .await
                                 .game
                                 .as_ref()
-                                .map_or(Err(vec![]), |g| g.check_for_move(&current_board));
+                                .map_or(Err(vec![]), |g| {
+                                    g.check_for_move(&current_board, hand_left)
+                                });
                         }
                         match &mut proposed_update {
                             Ok((update, player, cooldown)) => {
@@ -557,7 +1194,7 @@ impl AppState {
                     Err(obscured_coords) => {
                         // If the board is obscured in a small area (in case it's not actually obscured but
                         // some stones are out of place), track the obscured points
-                        if obscured_coords.len() < 3 {
+                        if obscured_coords.len() <= recognition.max_troublesome_points {
                             handle_troublesome_coords(&obscured_coords, &mut troublesome_points);
                         }
                     }
@@ -572,7 +1209,12 @@ impl AppState {
                     }
                 }
 
-                state_ref.write().await.troublesome_points = troublesome_points;
+                {
+                    let mut state = state_ref.write().await;
+                    state.troublesome_points = troublesome_points;
+                    state.stone_offsets = stone_offsets.clone();
+                    state.inference_stats = motion_gate.stats();
+                }
 
                 /// Marks the specified coordinates as troublesome.
                 fn handle_troublesome_coords(
@@ -600,23 +1242,39 @@ impl AppState {
     /// This will later be warped according to the display configuration.
     fn render_raw(&self, display_state: DisplayState, even_tick: bool) -> RgbaImage {
         let mut ctx = self.create_rendering_context();
+        self.render_contents(display_state, even_tick, &mut ctx);
+        ctx.into_image()
+    }
+
+    /// Renders the display in the space of the board image, as it is projected onto the board.
+    /// This is the same as warping the normalized display onto the board, but sharper.
+    fn render_board_display(&self, display_state: DisplayState, even_tick: bool) -> RgbImage {
+        let mut ctx = self.create_board_rendering_context();
+        self.render_contents(display_state, even_tick, &mut ctx);
+        ctx.into_image().convert()
+    }
 
+    /// Renders whatever the display state calls for into the rendering context.
+    fn render_contents(
+        &self,
+        display_state: DisplayState,
+        even_tick: bool,
+        ctx: &mut RenderingContext,
+    ) {
         match display_state {
             DisplayState::Calibrate => {
-                self.render_calibrate(&mut ctx);
+                self.render_calibrate(ctx);
             }
             DisplayState::Training(seed) => {
-                self.render_training(seed, &mut ctx);
+                self.render_training(seed, ctx);
             }
             DisplayState::Game => {
-                self.render_game(&mut ctx, even_tick);
+                self.render_game(ctx, even_tick);
             }
             DisplayState::GameOver(winner) => {
-                self.render_endgame(winner, &mut ctx);
+                self.render_endgame(winner, ctx);
             }
         }
-
-        ctx.into_image()
     }
 
     /// Creates a new rendering context.
@@ -641,6 +1299,23 @@ impl AppState {
         }
     }
 
+    /// Creates a rendering context with the size and scale of the board image.
+    fn create_board_rendering_context(&self) -> RenderingContext {
+        let img = RgbaImage::new(
+            self.config.board.width.get() * STONE_SIZE,
+            self.config.board.height.get() * STONE_SIZE,
+        );
+        // Each intersection is in the center of its stone-sized area of the board image
+        let origin = STONE_SIZE as f32 * 0.5;
+
+        RenderingContext {
+            img,
+            stone_size: STONE_SIZE as f32,
+            origin_x: origin,
+            origin_y: origin,
+        }
+    }
+
     /// Renders the calibration pattern.
     fn render_calibrate(&self, ctx: &mut RenderingContext) {
         // Draw a dot on every intersection
@@ -733,10 +1408,17 @@ impl AppState {
         ctx.fill_rectangle(-0.5, top, width, 1.0, color);
 
         // Blink points that the vision model is finding difficult
+        // If the stone there is off-centre, point in the direction it needs to be moved instead
         if even_tick {
             for y in 0..self.config.board.height.get() {
                 for x in 0..self.config.board.width.get() {
-                    if self.troublesome_points[y as usize][x as usize] >= 10 {
+                    if self.troublesome_points[y as usize][x as usize] < 10 {
+                        continue;
+                    }
+                    let (dx, dy) = self.stone_offsets[y as usize][x as usize];
+                    if dx.hypot(dy) > MAX_STONE_OFFSET {
+                        ctx.fill_arrow(x as f32, y as f32, -dx, -dy, Rgba([255, 0, 0, 255]));
+                    } else {
                         ctx.fill_circle(x as f32, y as f32, 1.5, Rgba([255, 0, 0, 255]));
                     }
                 }
@@ -852,6 +1534,33 @@ pub enum DisplayState {
     GameOver(SerializableColor),
 }
 
+/// How the board would be read with a candidate set of recognition settings.
+#[derive(Serialize)]
+pub struct RecognitionPreview {
+    /// The state of each intersection in row-major order: empty, black, white, or `?` if it's obscured.
+    pub board: Vec<Vec<char>>,
+    /// Whether the obscured intersections would be highlighted as misplaced stones.
+    pub troublesome: bool,
+}
+
+/// The vision model's prediction for an intersection in a single frame,
+/// along with how far the stone seems to be from the center of the intersection.
+#[derive(Clone, Copy)]
+pub struct RawPrediction {
+    pub probabilities: VisionModelOutput,
+    /// The offset of the stone in stone widths to the right and down, or zero if there is no stone.
+    pub offset: (f32, f32),
+}
+
+impl Serialize for RawPrediction {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // The offset follows the probabilities in the same array, so that clients reading only the probabilities keep working
+        let (none, black, white, obscured) = self.probabilities;
+        let (x, y) = self.offset;
+        (none, black, white, obscured, x, y).serialize(serializer)
+    }
+}
+
 /// Helper struct for rendering the display.
 struct RenderingContext {
     img: RgbaImage,
@@ -890,6 +1599,29 @@ impl RenderingContext {
         );
     }
 
+    /// Draws an arrow one and a half stones long, centered on the given point and pointing in the given direction.
+    fn fill_arrow(&mut self, x: f32, y: f32, dx: f32, dy: f32, color: Rgba<u8>) {
+        let length = dx.hypot(dy);
+        // Unit vectors along and across the arrow
+        let (ax, ay) = (dx / length, dy / length);
+        let (cx, cy) = (-ay, ax);
+        let point = |along: f32, across: f32| {
+            Point::new(x + ax * along + cx * across, y + ay * along + cy * across)
+        };
+        self.fill_polygon(
+            &[
+                point(-0.75, -0.15),
+                point(0.15, -0.15),
+                point(0.15, -0.45),
+                point(0.75, 0.0),
+                point(0.15, 0.45),
+                point(0.15, 0.15),
+                point(-0.75, 0.15),
+            ],
+            color,
+        );
+    }
+
     /// Draws a filled polygon.
     fn fill_polygon(&mut self, points: &[Point<f32>], color: Rgba<u8>) {
         let mapped_points = points
@@ -908,102 +1640,48 @@ impl RenderingContext {
     }
 }
 
-/// Tries to start capturing from a camera based on the configuration.
-fn start_camera(config: &CameraConfig) -> Option<Camera> {
-    // Try to find a camera with the given name
-    let cameras = nokhwa::query(ApiBackend::Auto).ok()?;
-    let camera_info = cameras
-        .into_iter()
-        .find(|camera| camera.human_name() == config.device)?;
-
-    // Create the camera with default/arbitrary settings (mainly to have it choose a frame format)
-    let mut camera = Camera::new(
-        camera_info.index().clone(),
-        RequestedFormat::new::<RgbFormat>(RequestedFormatType::None),
-    )
-    .ok()?;
-
-    // Request a specific resolution from the camera, without changing the frame format
-    camera
-        .set_camera_requset(RequestedFormat::new::<RgbFormat>(
-            RequestedFormatType::Closest(CameraFormat::new(
-                Resolution::new(config.width, config.height),
-                camera.frame_format(),
-                10,
-            )),
-        ))
-        .ok()?;
-
-    // Start capturing from the camera
-    camera.open_stream().ok()?;
-    Some(camera)
-}
-
-/// Tries to read a frame from the current camera.
-fn read_frame(camera: Option<&mut Camera>) -> Option<RgbImage> {
-    camera?.frame().ok()?.decode_image::<RgbFormat>().ok()
-}
-
-/// Runs the vision model on an image of the board and returns the state of each intersection.
-fn run_vision_model(
-    model: &VisionModel,
-    image: Rgb32FImage,
-    reference: Rgb32FImage,
-    device: Device,
-) -> Vec<Vec<VisionModelOutput>> {
-    let width = reference.width() / STONE_SIZE;
-    let height = reference.height() / STONE_SIZE;
-    let mut input = Vec::with_capacity((width * height) as usize);
-    for y in 0..height {
-        for x in 0..width {
-            input.push(read_tensor(
-                &image,
-                &reference,
-                x * STONE_SIZE,
-                y * STONE_SIZE,
-            ));
+/// Loads a vision model, returning the error message instead if it fails.
+fn load_model(config: &ModelConfig) -> (Option<LoadedModel>, Option<String>) {
+    match LoadedModel::load(config) {
+        Ok(model) => (Some(model), None),
+        Err(e) => {
+            println!("Failed to load the vision model: {}", e);
+            (None, Some(e.to_string()))
         }
     }
-    let output: Vec<Vec<f32>> = model
-        .forward(&Tensor::stack(&input, 0).to(device))
-        .softmax(1, Kind::Float)
-        .try_into()
-        .unwrap();
-
-    let mut result = Vec::with_capacity(height as usize);
-    for y in 0..height {
-        result.push(Vec::with_capacity(width as usize));
-        for x in 0..width {
-            let index = (y * width + x) as usize;
-            result[y as usize].push((
-                output[index][0],
-                output[index][1],
-                output[index][2],
-                output[index][3],
-            ));
+}
+
+/// Loads the fallback vision model if one is configured, returning the error message instead if it fails.
+fn load_fallback_model(config: &ModelConfig) -> (Option<LoadedModel>, Option<String>) {
+    let Some(path) = &config.fallback_path else {
+        return (None, None);
+    };
+    match LoadedModel::load_fallback(path) {
+        Ok(model) => (Some(model), None),
+        Err(e) => {
+            println!("Failed to load the fallback vision model: {}", e);
+            (None, Some(e.to_string()))
         }
     }
-    result
 }
 
 /// Calculates the most likely state of the board, or returns the list of obscured points.
-fn get_board(probabilities: &[Vec<VisionModelOutput>]) -> Result<Goban, Vec<Coord>> {
+fn get_board(
+    probabilities: &[Vec<VisionModelOutput>],
+    recognition: &RecognitionConfig,
+) -> Result<Goban, Vec<Coord>> {
     let mut goban = Goban::new((probabilities.len() as u8, probabilities[0].len() as u8));
     let mut obscured_coords = Vec::new();
     for (y, row) in probabilities.iter().enumerate() {
-        for (x, (empty, black, white, _)) in row.iter().enumerate() {
+        for (x, p) in row.iter().enumerate() {
             let coord = (x as u8, y as u8);
-            let color = if *empty > 0.5 {
-                continue;
-            } else if *black > 0.9 {
-                Color::Black
-            } else if *white > 0.9 {
-                Color::White
-            } else {
-                obscured_coords.push(coord);
-                continue;
-            };
-            goban.push(coord, color);
+            match classify_intersection(p, recognition) {
+                Some(Some(color)) => {
+                    goban.push(coord, color);
+                }
+                Some(None) => {}
+                None => obscured_coords.push(coord),
+            }
         }
     }
     if !obscured_coords.is_empty() {
@@ -1012,3 +1690,19 @@ fn get_board(probabilities: &[Vec<VisionModelOutput>]) -> Result<Goban, Vec<Coor
         Ok(goban)
     }
 }
+
+/// Decides the state of an intersection, or returns `None` if it's obscured.
+fn classify_intersection(
+    (empty, black, white, _): &VisionModelOutput,
+    recognition: &RecognitionConfig,
+) -> Option<Option<Color>> {
+    if *empty > recognition.empty_threshold {
+        Some(None)
+    } else if *black > recognition.black_threshold {
+        Some(Some(Color::Black))
+    } else if *white > recognition.white_threshold {
+        Some(Some(Color::White))
+    } else {
+        None
+    }
+}
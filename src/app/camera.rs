@@ -0,0 +1,608 @@
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use image::{ImageFormat, RgbImage};
+use nokhwa::{
+    Camera, NokhwaError,
+    pixel_format::RgbFormat,
+    utils::{
+        ApiBackend, CameraFormat, ControlValueSetter, FrameFormat, KnownCameraControl,
+        RequestedFormat, RequestedFormatType, Resolution,
+    },
+};
+
+use serde::Serialize;
+use tokio::sync::watch;
+
+use super::config::{CameraConfig, PixelFormat};
+use crate::error::SaigoError;
+
+/// The prefix of a camera device name that refers to a directory of recorded frames.
+pub const REPLAY_DEVICE_PREFIX: &str = "file://";
+
+/// The prefix of a camera device name that refers to an MJPEG stream over HTTP.
+pub const NETWORK_DEVICE_PREFIX: &str = "http://";
+
+/// The prefix of network streams that need TLS, which the HTTP client is built without.
+const SECURE_NETWORK_DEVICE_PREFIX: &str = "https://";
+
+/// How long to wait for a frame from a network stream before reporting an error.
+const NETWORK_FRAME_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The largest amount of data to buffer while looking for the end of a JPEG image in a network stream.
+const MAX_NETWORK_BUFFER: usize = 16 * 1024 * 1024;
+
+/// Checks whether a device name refers to a source that can't be discovered by querying the cameras,
+/// so it has to be listed explicitly when it is configured.
+pub fn is_custom_device(device: &str) -> bool {
+    device.starts_with(REPLAY_DEVICE_PREFIX) || device.starts_with(NETWORK_DEVICE_PREFIX)
+}
+
+/// The state of the camera, as tracked by the camera loop.
+#[derive(Clone, Default, PartialEq, Serialize)]
+pub struct CameraStatus {
+    /// Whether the camera device is open.
+    pub connected: bool,
+    /// Whether frames are currently being received from the camera.
+    pub streaming: bool,
+    /// When the last frame was received, in milliseconds since the Unix epoch.
+    pub last_frame: Option<u64>,
+    /// The resolution of the frames actually received.
+    pub width: u32,
+    pub height: u32,
+    /// The pixel format negotiated with the camera.
+    pub format: Option<String>,
+    /// The most recent error from opening or reading the camera.
+    pub error: Option<String>,
+    /// Whether the board appears to have moved relative to the camera since the corners were set.
+    pub moved: bool,
+    /// How far the grid has drifted from the reference image, in stone widths.
+    pub drift: f32,
+    /// The number of times the corner points have been corrected since the server started.
+    pub realignments: u32,
+}
+
+impl CameraStatus {
+    /// Updates the status, only notifying receivers if something other than the time of the last frame changed.
+    pub fn update(broadcast: &watch::Sender<CameraStatus>, update: impl FnOnce(&mut CameraStatus)) {
+        broadcast.send_if_modified(|status| {
+            let previous = status.clone();
+            update(status);
+            CameraStatus {
+                last_frame: previous.last_frame,
+                ..status.clone()
+            } != previous
+        });
+    }
+
+    /// Records that a frame was received.
+    pub fn frame_received(&mut self, frame: &RgbImage) {
+        self.streaming = true;
+        self.last_frame = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|time| time.as_millis() as u64);
+        (self.width, self.height) = frame.dimensions();
+        self.error = None;
+    }
+}
+
+/// A source of camera frames.
+pub trait FrameSource: Send {
+    /// Reads the next frame.
+    fn read_frame(&mut self) -> Result<RgbImage, SaigoError>;
+
+    /// Describes the format the frames are captured in.
+    fn format(&self) -> String;
+}
+
+impl FrameSource for Camera {
+    fn read_frame(&mut self) -> Result<RgbImage, SaigoError> {
+        Ok(self.frame()?.decode_image::<RgbFormat>()?)
+    }
+
+    fn format(&self) -> String {
+        let format = self.camera_format();
+        format!("{} @ {} fps", format.format(), format.frame_rate())
+    }
+}
+
+/// Replays a directory of recorded frames in a loop.
+/// Frames are PNG images with numeric names, as produced by `gather-td` or `saigo --record`.
+pub struct ReplaySource {
+    files: Vec<PathBuf>,
+    index: usize,
+}
+
+impl ReplaySource {
+    /// Opens a directory of recorded frames, if it contains any.
+    pub fn open(dir: &Path) -> Result<Self, SaigoError> {
+        let mut indexed_files: Vec<(PathBuf, usize)> = dir
+            .read_dir()?
+            .flatten()
+            .filter_map(|entry| {
+                let path = entry.path();
+                // Only include PNG files whose names are numbers
+                if path.extension()? != "png" || !path.is_file() {
+                    return None;
+                }
+                let index = path.file_stem()?.to_str()?.parse::<usize>().ok()?;
+                Some((path, index))
+            })
+            .collect();
+        if indexed_files.is_empty() {
+            return Err(SaigoError::CameraUnavailable(format!(
+                "No recorded frames found in {}.",
+                dir.display()
+            )));
+        }
+
+        // Sort the file names numerically
+        indexed_files.sort_unstable_by_key(|(_, index)| *index);
+        Ok(Self {
+            files: indexed_files.into_iter().map(|(path, _)| path).collect(),
+            index: 0,
+        })
+    }
+}
+
+impl FrameSource for ReplaySource {
+    fn read_frame(&mut self) -> Result<RgbImage, SaigoError> {
+        let path = &self.files[self.index];
+        self.index = (self.index + 1) % self.files.len();
+        Ok(image::open(path)?.into_rgb8())
+    }
+
+    fn format(&self) -> String {
+        "PNG".to_string()
+    }
+}
+
+/// Receives frames from an MJPEG stream over HTTP, such as from a phone or a Raspberry Pi camera.
+/// The stream is read on a separate thread, since the HTTP client blocks.
+pub struct NetworkSource {
+    url: String,
+    frames: Receiver<Result<RgbImage, String>>,
+}
+
+impl NetworkSource {
+    /// Starts reading the stream at the given URL.
+    pub fn connect(url: &str) -> Self {
+        // Only keep a couple of frames, so that frames are dropped rather than piling up if they aren't being read
+        let (sender, frames) = mpsc::sync_channel(2);
+        let thread_url = url.to_string();
+        thread::spawn(move || {
+            if let Err(e) = read_mjpeg_stream(&thread_url, &sender) {
+                let _ = sender.try_send(Err(e));
+            }
+        });
+        Self {
+            url: url.to_string(),
+            frames,
+        }
+    }
+}
+
+impl FrameSource for NetworkSource {
+    fn read_frame(&mut self) -> Result<RgbImage, SaigoError> {
+        // Wait for a frame, then skip ahead to the most recent one
+        let mut frame = self.frames.recv_timeout(NETWORK_FRAME_TIMEOUT);
+        while let Ok(next) = self.frames.try_recv() {
+            frame = Ok(next);
+        }
+        match frame {
+            Ok(frame) => frame.map_err(SaigoError::CameraUnavailable),
+            Err(RecvTimeoutError::Timeout) => Err(SaigoError::CameraUnavailable(format!(
+                "Timed out waiting for a frame from {}.",
+                self.url
+            ))),
+            Err(RecvTimeoutError::Disconnected) => Err(SaigoError::CameraUnavailable(format!(
+                "The stream from {} ended.",
+                self.url
+            ))),
+        }
+    }
+
+    fn format(&self) -> String {
+        "MJPEG over HTTP".to_string()
+    }
+}
+
+/// Reads JPEG images from an MJPEG stream until it ends or the receiver is dropped.
+/// Rather than parsing the multipart boundaries, this looks for the start and end of each image,
+/// which works with any stream of concatenated JPEG images.
+fn read_mjpeg_stream(
+    url: &str,
+    sender: &SyncSender<Result<RgbImage, String>>,
+) -> Result<(), String> {
+    let mut response = reqwest::blocking::Client::builder()
+        // The stream never finishes, so only limit the time to connect
+        .timeout(None)
+        .connect_timeout(NETWORK_FRAME_TIMEOUT)
+        .build()
+        .and_then(|client| client.get(url).send())
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| format!("Failed to connect to {}: {}", url, e))?;
+
+    let mut splitter = JpegSplitter::new();
+    let mut chunk = vec![0; 64 * 1024];
+    loop {
+        let read = response
+            .read(&mut chunk)
+            .map_err(|e| format!("Failed to read from {}: {}", url, e))?;
+        if read == 0 {
+            return Ok(());
+        }
+        splitter.buffer.extend_from_slice(&chunk[..read]);
+
+        // Extract every complete image in the buffer
+        while let Some(jpeg) = splitter.next_image() {
+            let frame = image::load_from_memory_with_format(&jpeg, ImageFormat::Jpeg)
+                .map(|image| image.into_rgb8())
+                .map_err(|e| format!("Failed to decode a frame from {}: {}", url, e));
+            match sender.try_send(frame) {
+                Ok(()) | Err(TrySendError::Full(_)) => {}
+                Err(TrySendError::Disconnected(_)) => return Ok(()),
+            }
+        }
+
+        if splitter.buffer.len() > MAX_NETWORK_BUFFER {
+            return Err(format!("{} is not sending JPEG images.", url));
+        }
+    }
+}
+
+/// Splits a stream of bytes into JPEG images by walking over the segments of each image.
+/// Segments are skipped by their length, so that markers inside them,
+/// such as the end of an embedded EXIF thumbnail, aren't mistaken for the end of the image.
+/// The position is kept between reads, so that each byte is only scanned once.
+struct JpegSplitter {
+    /// The bytes received but not yet returned as images.
+    buffer: Vec<u8>,
+    /// Whether the buffer starts with the start of an image.
+    in_image: bool,
+    /// How far the buffer has been scanned.
+    position: usize,
+    /// Whether the position is in the compressed image data, which has no length and ends at the next marker.
+    in_scan: bool,
+}
+
+impl JpegSplitter {
+    fn new() -> Self {
+        Self {
+            buffer: vec![],
+            in_image: false,
+            position: 0,
+            in_scan: false,
+        }
+    }
+
+    /// Removes the next complete image from the buffer, or returns None if more bytes are needed.
+    fn next_image(&mut self) -> Option<Vec<u8>> {
+        loop {
+            if !self.in_image {
+                // Discard anything before the start of the next image
+                let Some(start) = find(&self.buffer, &[0xFF, 0xD8], self.position) else {
+                    // Keep the last byte, which could be the first half of the start marker
+                    self.buffer.drain(..self.buffer.len().saturating_sub(1));
+                    self.position = 0;
+                    return None;
+                };
+                self.buffer.drain(..start);
+                self.in_image = true;
+                self.position = 2;
+                self.in_scan = false;
+            }
+
+            if self.in_scan {
+                // Bytes stuffed after 0xFF, restart markers and fill bytes are part of the compressed data
+                let marker = (self.position..self.buffer.len().saturating_sub(1)).find(|&i| {
+                    self.buffer[i] == 0xFF
+                        && !matches!(self.buffer[i + 1], 0x00 | 0xD0..=0xD7 | 0xFF)
+                });
+                let Some(marker) = marker else {
+                    self.position = self.buffer.len().saturating_sub(1).max(self.position);
+                    return None;
+                };
+                self.position = marker;
+                self.in_scan = false;
+            }
+
+            let Some(&[prefix, marker]) = self.buffer.get(self.position..self.position + 2) else {
+                return None;
+            };
+            if prefix != 0xFF {
+                // The image is corrupt, so look for the start of the next one
+                self.in_image = false;
+                continue;
+            }
+            match marker {
+                // The end of the image
+                0xD9 => {
+                    let end = self.position + 2;
+                    self.in_image = false;
+                    self.position = 0;
+                    return Some(self.buffer.drain(..end).collect());
+                }
+                // Fill bytes before a marker
+                0xFF => self.position += 1,
+                // Markers without a segment
+                0x01 | 0xD0..=0xD8 => self.position += 2,
+                // Segments starting with their length, which includes the length itself
+                _ => {
+                    let Some(&[high, low]) = self.buffer.get(self.position + 2..self.position + 4)
+                    else {
+                        return None;
+                    };
+                    self.position += 2 + u16::from_be_bytes([high, low]) as usize;
+                    // The start of scan segment is followed by the compressed image data
+                    self.in_scan = marker == 0xDA;
+                }
+            }
+        }
+    }
+}
+
+/// Finds the first occurrence of a byte sequence, starting from the given index.
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|index| index + from)
+}
+
+/// The V4L2 ids of the controls that switch automatic exposure, white balance, and focus, which nokhwa doesn't name.
+const AUTO_EXPOSURE_CONTROL: u32 = 0x009a_0901;
+const AUTO_WHITE_BALANCE_CONTROL: u32 = 0x0098_090c;
+const AUTO_FOCUS_CONTROL: u32 = 0x009a_090c;
+
+/// The controls for manual exposure and focus.
+/// On Linux, nokhwa maps its exposure and focus controls to ones that most webcams don't support,
+/// rather than to the absolute exposure time and focus distance.
+#[cfg(target_os = "linux")]
+const EXPOSURE_CONTROL: KnownCameraControl = KnownCameraControl::Other(0x009a_0902);
+#[cfg(target_os = "linux")]
+const FOCUS_CONTROL: KnownCameraControl = KnownCameraControl::Other(0x009a_090a);
+#[cfg(not(target_os = "linux"))]
+const EXPOSURE_CONTROL: KnownCameraControl = KnownCameraControl::Exposure;
+#[cfg(not(target_os = "linux"))]
+const FOCUS_CONTROL: KnownCameraControl = KnownCameraControl::Focus;
+
+/// The values of the V4L2 auto exposure control.
+/// Most webcams only support manual and aperture priority, which is their automatic mode.
+const EXPOSURE_MANUAL: i64 = 1;
+const EXPOSURE_APERTURE_PRIORITY: i64 = 3;
+
+/// Sets a camera control by its V4L2 id.
+#[cfg(target_os = "linux")]
+fn set_v4l2_control(
+    camera: &mut Camera,
+    id: u32,
+    value: ControlValueSetter,
+) -> Result<(), NokhwaError> {
+    camera.set_camera_control(KnownCameraControl::Other(id as u128), value)
+}
+
+/// Reports that V4L2 controls aren't available on this platform.
+#[cfg(not(target_os = "linux"))]
+fn set_v4l2_control(
+    _camera: &mut Camera,
+    _id: u32,
+    _value: ControlValueSetter,
+) -> Result<(), NokhwaError> {
+    Err(NokhwaError::NotImplementedError(
+        "Switching automatic modes is only supported on Linux.".to_string(),
+    ))
+}
+
+/// Starts capturing frames based on the configuration.
+/// If a replay directory is given, it takes precedence over the configured device.
+pub fn start_camera(
+    config: &CameraConfig,
+    replay: Option<&Path>,
+) -> Result<Box<dyn FrameSource>, SaigoError> {
+    if let Some(dir) = replay {
+        return Ok(Box::new(ReplaySource::open(dir)?));
+    }
+    if let Some(dir) = config.device.strip_prefix(REPLAY_DEVICE_PREFIX) {
+        return Ok(Box::new(ReplaySource::open(Path::new(dir))?));
+    }
+    if config.device.starts_with(SECURE_NETWORK_DEVICE_PREFIX) {
+        return Err(SaigoError::CameraUnavailable(
+            "Streams over HTTPS aren't supported, since Saigo is built without TLS. Use an http:// URL instead."
+                .to_string(),
+        ));
+    }
+    if config.device.starts_with(NETWORK_DEVICE_PREFIX) {
+        return Ok(Box::new(NetworkSource::connect(&config.device)));
+    }
+    Ok(Box::new(start_nokhwa_camera(config)?))
+}
+
+/// Starts capturing from a physical camera.
+fn start_nokhwa_camera(config: &CameraConfig) -> Result<Camera, SaigoError> {
+    if config.device.is_empty() {
+        return Err(SaigoError::CameraUnavailable(
+            "No camera is configured.".to_string(),
+        ));
+    }
+
+    // Try to find a camera with the given name
+    let cameras = nokhwa::query(ApiBackend::Auto)?;
+    let camera_info = cameras
+        .into_iter()
+        .find(|camera| camera.human_name() == config.device)
+        .ok_or_else(|| {
+            SaigoError::CameraUnavailable(format!("Camera '{}' was not found.", config.device))
+        })?;
+
+    // Create the camera with default/arbitrary settings (mainly to have it choose a frame format)
+    let mut camera = Camera::new(
+        camera_info.index().clone(),
+        RequestedFormat::new::<RgbFormat>(RequestedFormatType::None),
+    )?;
+
+    // Request a specific resolution and frame rate from the camera,
+    // keeping the camera's choice of frame format unless one is configured
+    let frame_format = match config.frame_format {
+        Some(PixelFormat::Mjpeg) => FrameFormat::MJPEG,
+        Some(PixelFormat::Yuyv) => FrameFormat::YUYV,
+        None => camera.frame_format(),
+    };
+    camera.set_camera_requset(RequestedFormat::new::<RgbFormat>(
+        RequestedFormatType::Closest(CameraFormat::new(
+            Resolution::new(config.width, config.height),
+            frame_format,
+            config.frame_rate,
+        )),
+    ))?;
+
+    // Start capturing from the camera
+    camera.open_stream()?;
+
+    // Switch the automatic modes first, since the camera may ignore manual values while they are on
+    let manual = &config.controls;
+    let modes = [
+        (
+            "auto exposure",
+            AUTO_EXPOSURE_CONTROL,
+            manual.auto_exposure.map(|auto| {
+                ControlValueSetter::Integer(if auto {
+                    EXPOSURE_APERTURE_PRIORITY
+                } else {
+                    EXPOSURE_MANUAL
+                })
+            }),
+        ),
+        (
+            "auto white balance",
+            AUTO_WHITE_BALANCE_CONTROL,
+            manual.auto_white_balance.map(ControlValueSetter::Boolean),
+        ),
+        // Autofocus would override the configured focus, and has no setting of its own
+        (
+            "auto focus",
+            AUTO_FOCUS_CONTROL,
+            manual
+                .focus
+                .filter(|_| cfg!(target_os = "linux"))
+                .map(|_| ControlValueSetter::Boolean(false)),
+        ),
+    ];
+    for (name, id, value) in modes
+        .into_iter()
+        .filter_map(|(name, id, value)| Some((name, id, value?)))
+    {
+        if let Err(e) = set_v4l2_control(&mut camera, id, value) {
+            println!("Failed to set camera control {}: {}", name, e);
+        }
+    }
+
+    // Apply the configured manual controls, leaving the rest to the camera
+    let controls = [
+        (EXPOSURE_CONTROL, manual.exposure),
+        (KnownCameraControl::Gain, manual.gain),
+        (KnownCameraControl::WhiteBalance, manual.white_balance),
+        (FOCUS_CONTROL, manual.focus),
+        (KnownCameraControl::Brightness, manual.brightness),
+    ];
+    for (control, value) in controls
+        .into_iter()
+        .filter_map(|(control, value)| Some((control, value?)))
+    {
+        if let Err(e) = camera.set_camera_control(control, ControlValueSetter::Integer(value)) {
+            println!("Failed to set camera control {:?}: {}", control, e);
+        }
+    }
+
+    Ok(camera)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a small JPEG with an EXIF thumbnail, byte stuffing, a restart marker, and fill bytes.
+    fn jpeg(id: u8) -> Vec<u8> {
+        let thumbnail = [0xFF, 0xD8, 0xFF, 0xDB, 0x00, 0x03, 0x01, 0xFF, 0xD9];
+        let mut exif = b"Exif\0\0".to_vec();
+        exif.extend(thumbnail);
+        let mut bytes = vec![0xFF, 0xD8];
+        // An APP1 segment containing the thumbnail, with its own start and end markers
+        bytes.extend([0xFF, 0xE1]);
+        bytes.extend((exif.len() as u16 + 2).to_be_bytes());
+        bytes.extend(exif);
+        // A quantization table that happens to contain the end marker
+        bytes.extend([0xFF, 0xDB, 0x00, 0x05, id, 0xFF, 0xD9]);
+        // The start of scan, followed by compressed data with stuffed bytes and a restart marker
+        bytes.extend([0xFF, 0xDA, 0x00, 0x04, 0x01, 0x02]);
+        bytes.extend([0x12, 0xFF, 0x00, 0x34, 0xFF, 0xD0, 0x56, 0xFF, 0x00, id]);
+        // Fill bytes before the end of the image
+        bytes.extend([0xFF, 0xFF, 0xFF, 0xD9]);
+        bytes
+    }
+
+    /// Feeds the bytes to a splitter in chunks of the given size, returning the images it finds.
+    fn split(bytes: &[u8], chunk_size: usize) -> Vec<Vec<u8>> {
+        let mut splitter = JpegSplitter::new();
+        let mut images = vec![];
+        for chunk in bytes.chunks(chunk_size) {
+            splitter.buffer.extend_from_slice(chunk);
+            while let Some(image) = splitter.next_image() {
+                images.push(image);
+            }
+        }
+        images
+    }
+
+    #[test]
+    fn splits_across_chunks() {
+        let stream = [jpeg(1), jpeg(2)].concat();
+        for chunk_size in 1..=stream.len() {
+            assert_eq!(
+                split(&stream, chunk_size),
+                [jpeg(1), jpeg(2)],
+                "{}",
+                chunk_size
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_scan_data_and_thumbnail() {
+        let images = split(&jpeg(1), 1000);
+        assert_eq!(images, [jpeg(1)]);
+    }
+
+    #[test]
+    fn skips_garbage_and_boundaries() {
+        let mut stream = b"garbage\xFF\x00--frame\r\nContent-Type: image/jpeg\r\n\r\n".to_vec();
+        stream.extend(jpeg(1));
+        stream.extend(b"\r\n--frame\r\nContent-Type: image/jpeg\r\nContent-Length: 44\r\n\r\n");
+        stream.extend(jpeg(2));
+        stream.extend(b"\r\n--frame\r\n");
+        for chunk_size in [1, 3, 7, 1000] {
+            assert_eq!(
+                split(&stream, chunk_size),
+                [jpeg(1), jpeg(2)],
+                "{}",
+                chunk_size
+            );
+        }
+    }
+
+    #[test]
+    fn recovers_from_a_truncated_image() {
+        let mut stream = jpeg(1);
+        stream.truncate(20);
+        stream.extend(b"\r\n--frame\r\n\r\n");
+        stream.extend(jpeg(2));
+        assert_eq!(split(&stream, 1000), [jpeg(2)]);
+    }
+}
@@ -6,6 +6,7 @@ use std::{
 
 use confy::ConfyError;
 use image::RgbImage;
+use saigo::recognizer::RecognizerKind;
 use serde::{Deserialize, Serialize};
 
 use crate::error::SaigoError;
@@ -16,6 +17,10 @@ pub struct Config {
     pub board: BoardConfig,
     pub display: DisplayConfig,
     pub camera: CameraConfig,
+    #[serde(default)]
+    pub model: ModelConfig,
+    #[serde(default)]
+    pub recognition: RecognitionConfig,
 }
 
 impl Config {
@@ -191,6 +196,18 @@ pub struct CameraConfig {
     pub top_right: Point,
     pub bottom_left: Point,
     pub bottom_right: Point,
+    #[serde(default)]
+    pub distortion: LensDistortion,
+    #[serde(default)]
+    pub auto_realign: bool,
+    #[serde(default)]
+    pub adaptive_reference: bool,
+    #[serde(default = "default_frame_rate")]
+    pub frame_rate: u32,
+    #[serde(default)]
+    pub frame_format: Option<PixelFormat>,
+    #[serde(default)]
+    pub controls: CameraControls,
     #[serde(skip)]
     pub reference_image: Option<RgbImage>,
 }
@@ -205,14 +222,110 @@ impl Default for CameraConfig {
             top_right: Point { x: 0.64, y: 0.25 },
             bottom_left: Point { x: 0.36, y: 0.75 },
             bottom_right: Point { x: 0.64, y: 0.75 },
+            distortion: LensDistortion::default(),
+            auto_realign: false,
+            adaptive_reference: false,
+            frame_rate: default_frame_rate(),
+            frame_format: None,
+            controls: CameraControls::default(),
             reference_image: None,
         }
     }
 }
 
+fn default_frame_rate() -> u32 {
+    10
+}
+
+/// A pixel format that can be requested from the camera.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum PixelFormat {
+    Mjpeg,
+    Yuyv,
+}
+
+/// Manual values for the camera's image controls.
+/// Controls that are `None` are left at whatever the camera chooses.
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CameraControls {
+    /// Whether the camera adjusts its exposure by itself, which fights the changing light from the projector.
+    #[serde(default)]
+    pub auto_exposure: Option<bool>,
+    /// Whether the camera adjusts its white balance by itself.
+    #[serde(default)]
+    pub auto_white_balance: Option<bool>,
+    pub exposure: Option<i64>,
+    pub gain: Option<i64>,
+    pub white_balance: Option<i64>,
+    pub focus: Option<i64>,
+    pub brightness: Option<i64>,
+}
+
+/// The settings for the vision model.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ModelConfig {
+    #[serde(default)]
+    pub recognizer: RecognizerKind,
+    pub path: String,
+    /// The model used while there is no reference image, which must not read one.
+    #[serde(default)]
+    pub fallback_path: Option<String>,
+}
+
+impl Default for ModelConfig {
+    fn default() -> Self {
+        Self {
+            recognizer: RecognizerKind::default(),
+            // Without LibTorch, only models exported to ONNX can be loaded
+            path: if cfg!(feature = "tch") {
+                "model.safetensors"
+            } else {
+                "model.onnx"
+            }
+            .to_string(),
+            fallback_path: None,
+        }
+    }
+}
+
+/// The settings used to decide the state of the board from the vision model's probabilities.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RecognitionConfig {
+    /// The probability above which an intersection is considered empty.
+    pub empty_threshold: f32,
+    /// The probability above which an intersection is considered to have a black stone.
+    pub black_threshold: f32,
+    /// The probability above which an intersection is considered to have a white stone.
+    pub white_threshold: f32,
+    /// The largest number of uncertain intersections that are treated as misplaced stones rather than a hand.
+    pub max_troublesome_points: usize,
+}
+
+impl Default for RecognitionConfig {
+    fn default() -> Self {
+        Self {
+            empty_threshold: 0.5,
+            black_threshold: 0.9,
+            white_threshold: 0.9,
+            max_troublesome_points: 2,
+        }
+    }
+}
+
 /// A point in 2D space.
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Point {
     pub x: f32,
     pub y: f32,
 }
+
+/// The coefficients of the camera's lens distortion.
+/// `k1` and `k2` are the radial coefficients, and `p1` and `p2` are the tangential coefficients.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct LensDistortion {
+    pub k1: f32,
+    pub k2: f32,
+    pub p1: f32,
+    pub p2: f32,
+}
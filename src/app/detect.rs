@@ -0,0 +1,294 @@
+use std::num::NonZero;
+
+use image::{GrayImage, RgbImage, buffer::ConvertBuffer};
+use imageproc::{
+    edges::canny,
+    hough::{LineDetectionOptions, PolarLine, detect_lines},
+};
+use serde::Serialize;
+
+use super::config::{BoardConfig, CameraConfig, Point};
+
+/// The standard board sizes, used to sanity check the number of detected grid lines.
+const STANDARD_SIZES: [usize; 3] = [9, 13, 19];
+
+/// The result of detecting the board in a camera frame.
+#[derive(Serialize)]
+pub struct BoardDetection {
+    /// The camera configuration with the detected corner points.
+    pub camera: CameraConfig,
+    /// The board size inferred from the number of grid lines.
+    pub board: BoardConfig,
+    /// How well the detected lines match a regular grid, from 0 to 1.
+    pub confidence: f32,
+}
+
+/// A grid line in polar form, with its angle in radians.
+#[derive(Clone, Copy)]
+struct Line {
+    angle: f32,
+    r: f32,
+}
+
+impl Line {
+    /// Finds the intersection of two lines.
+    fn intersect(&self, other: &Line) -> Option<(f32, f32)> {
+        let (s1, c1) = self.angle.sin_cos();
+        let (s2, c2) = other.angle.sin_cos();
+        let det = c1 * s2 - s1 * c2;
+        if det.abs() < 1e-6 {
+            return None;
+        }
+        Some((
+            (self.r * s2 - other.r * s1) / det,
+            (c1 * other.r - c2 * self.r) / det,
+        ))
+    }
+}
+
+/// Finds the grid lines of the board in a camera frame and proposes corner points and a board size.
+/// The corners are labelled to stay as close as possible to the current configuration,
+/// so that the orientation chosen by the user is preserved.
+pub fn detect_board(frame: &RgbImage, current: &CameraConfig) -> Option<BoardDetection> {
+    let (width, height) = frame.dimensions();
+    let gray: GrayImage = frame.convert();
+    let edges = canny(&gray, 20.0, 50.0);
+
+    // Grid lines span a large part of the frame, so require a correspondingly large number of votes
+    let lines = detect_lines(
+        &edges,
+        LineDetectionOptions {
+            vote_threshold: width.min(height) / 4,
+            suppression_radius: 4,
+        },
+    );
+
+    // Split the lines into the two directions of the grid
+    let angle = dominant_angle(&lines)?;
+    let (mut family_a, mut family_b): (Vec<Line>, Vec<Line>) = (vec![], vec![]);
+    for line in &lines {
+        if let Some(line) = orient(line, angle) {
+            family_a.push(line);
+        } else if let Some(line) = orient(line, angle + 90) {
+            family_b.push(line);
+        }
+    }
+    let (family_a, score_a) = find_grid_lines(&mut family_a)?;
+    let (family_b, score_b) = find_grid_lines(&mut family_b)?;
+
+    // Find the corners, in order around the outline
+    let first_a = family_a.first()?;
+    let last_a = family_a.last()?;
+    let first_b = family_b.first()?;
+    let last_b = family_b.last()?;
+    let outline = [
+        first_a.intersect(first_b)?,
+        first_a.intersect(last_b)?,
+        last_a.intersect(last_b)?,
+        last_a.intersect(first_b)?,
+    ]
+    .map(|(x, y)| (x / width as f32, y / height as f32));
+
+    // The edges of the outline alternate between running along lines of family A and family B
+    let (corners, along_a) = label_corners(outline, current);
+    let (columns, rows) = if along_a {
+        (family_b.len(), family_a.len())
+    } else {
+        (family_a.len(), family_b.len())
+    };
+
+    let mut confidence = score_a * score_b;
+    if columns != rows || !STANDARD_SIZES.contains(&columns) {
+        confidence *= 0.5;
+    }
+
+    let [top_left, top_right, bottom_right, bottom_left] = corners.map(|(x, y)| Point { x, y });
+    Some(BoardDetection {
+        camera: CameraConfig {
+            top_left,
+            top_right,
+            bottom_left,
+            bottom_right,
+            ..current.clone()
+        },
+        board: BoardConfig {
+            width: NonZero::new(columns as u32)?,
+            height: NonZero::new(rows as u32)?,
+        },
+        confidence,
+    })
+}
+
+/// Finds the most common line angle in degrees.
+fn dominant_angle(lines: &[PolarLine]) -> Option<u32> {
+    // Count lines in both directions together, since the grid has two perpendicular directions
+    let mut histogram = [0u32; 90];
+    for line in lines {
+        histogram[(line.angle_in_degrees % 90) as usize] += 1;
+    }
+
+    // Smooth the histogram to account for perspective
+    let (angle, _) = (0..90)
+        .map(|angle| {
+            let votes: u32 = (0..7).map(|i| histogram[(angle + 90 + i - 3) % 90]).sum();
+            (angle as u32, votes)
+        })
+        .max_by_key(|(_, votes)| *votes)?;
+    Some(angle)
+}
+
+/// Converts a line to a consistent orientation relative to the given angle in degrees,
+/// or returns `None` if the line is not roughly parallel to the angle.
+fn orient(line: &PolarLine, angle: u32) -> Option<Line> {
+    const TOLERANCE: i32 = 20;
+    let mut difference = line.angle_in_degrees as i32 - (angle % 180) as i32;
+    let mut r = line.r;
+
+    // Lines are equivalent when rotated half a turn with the distance negated
+    if difference > 90 {
+        difference -= 180;
+        r = -r;
+    } else if difference < -90 {
+        difference += 180;
+        r = -r;
+    }
+    if difference.abs() > TOLERANCE {
+        return None;
+    }
+
+    Some(Line {
+        angle: ((angle % 180) as i32 + difference) as f32 * std::f32::consts::PI / 180.0,
+        r,
+    })
+}
+
+/// Finds the longest run of regularly spaced parallel lines,
+/// returning the lines in order and a score for how regular the spacing is.
+fn find_grid_lines(lines: &mut [Line]) -> Option<(Vec<Line>, f32)> {
+    lines.sort_by(|a, b| a.r.total_cmp(&b.r));
+
+    // Merge lines that are very close together, such as the two edges of a single grid line
+    let mut merged: Vec<(Line, u32)> = vec![];
+    for line in lines.iter() {
+        match merged.last_mut() {
+            Some((last, count)) if line.r - last.r / *count as f32 <= 4.0 => {
+                last.r += line.r;
+                last.angle += line.angle;
+                *count += 1;
+            }
+            _ => merged.push((*line, 1)),
+        }
+    }
+    let merged: Vec<Line> = merged
+        .into_iter()
+        .map(|(line, count)| Line {
+            angle: line.angle / count as f32,
+            r: line.r / count as f32,
+        })
+        .collect();
+
+    // Find the longest chain where each gap is similar to the previous one,
+    // which allows for the gradual change in spacing caused by perspective
+    let mut best = 0..0;
+    let mut start = 0;
+    for i in 1..merged.len() {
+        let gap = merged[i].r - merged[i - 1].r;
+        if i - start >= 2 {
+            let previous_gap = merged[i - 1].r - merged[i - 2].r;
+            let ratio = gap / previous_gap;
+            if !(0.75..=1.33).contains(&ratio) {
+                start = i - 1;
+            }
+        }
+        if i + 1 - start > best.len() {
+            best = start..i + 1;
+        }
+    }
+
+    // Trim the chain to the largest standard board size, dropping the most irregular ends
+    let mut grid = merged[best].to_vec();
+    while grid.len() > STANDARD_SIZES[STANDARD_SIZES.len() - 1] {
+        let n = grid.len();
+        let median = median_gap(&grid);
+        let first_error = ((grid[1].r - grid[0].r) - median).abs();
+        let last_error = ((grid[n - 1].r - grid[n - 2].r) - median).abs();
+        if first_error > last_error {
+            grid.remove(0);
+        } else {
+            grid.pop();
+        }
+    }
+    if grid.len() < STANDARD_SIZES[0] {
+        return None;
+    }
+
+    // Score the regularity by fitting the gaps to a straight line
+    let gaps: Vec<f32> = grid.windows(2).map(|pair| pair[1].r - pair[0].r).collect();
+    let n = gaps.len() as f32;
+    let mean_index = (n - 1.0) * 0.5;
+    let mean_gap = gaps.iter().sum::<f32>() / n;
+    let covariance: f32 = gaps
+        .iter()
+        .enumerate()
+        .map(|(i, gap)| (i as f32 - mean_index) * (gap - mean_gap))
+        .sum();
+    let variance: f32 = (0..gaps.len())
+        .map(|i| (i as f32 - mean_index).powi(2))
+        .sum();
+    let slope = covariance / variance;
+    let residual = (gaps
+        .iter()
+        .enumerate()
+        .map(|(i, gap)| (gap - (mean_gap + slope * (i as f32 - mean_index))).powi(2))
+        .sum::<f32>()
+        / n)
+        .sqrt();
+    let score = (1.0 - 4.0 * residual / mean_gap).clamp(0.0, 1.0);
+
+    Some((grid, score))
+}
+
+/// Calculates the median distance between consecutive lines.
+fn median_gap(lines: &[Line]) -> f32 {
+    let mut gaps: Vec<f32> = lines.windows(2).map(|pair| pair[1].r - pair[0].r).collect();
+    gaps.sort_by(f32::total_cmp);
+    gaps[gaps.len() / 2]
+}
+
+/// Labels the corners of the outline as top left, top right, bottom right, and bottom left,
+/// choosing the rotation and reflection closest to the current configuration.
+/// Also returns whether the top edge runs along a line of the first family.
+fn label_corners(outline: [(f32, f32); 4], current: &CameraConfig) -> ([(f32, f32); 4], bool) {
+    let targets = [
+        &current.top_left,
+        &current.top_right,
+        &current.bottom_right,
+        &current.bottom_left,
+    ];
+    let mut best = (outline, true);
+    let mut best_error = f32::INFINITY;
+    for rotation in 0..4 {
+        for reflect in [false, true] {
+            let corners: [(f32, f32); 4] = std::array::from_fn(|i| {
+                let index = if reflect { 4 - i } else { i };
+                outline[(index + rotation) % 4]
+            });
+            let error: f32 = corners
+                .iter()
+                .zip(targets)
+                .map(|((x, y), target)| (x - target.x).powi(2) + (y - target.y).powi(2))
+                .sum();
+            if error < best_error {
+                best_error = error;
+                // The outline's first edge runs along family A, and each step around the outline alternates
+                let first_edge = if reflect {
+                    (rotation + 3) % 4
+                } else {
+                    rotation
+                };
+                best = (corners, first_edge % 2 == 0);
+            }
+        }
+    }
+    best
+}
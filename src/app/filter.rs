@@ -0,0 +1,118 @@
+use saigo::recognizer::VisionModelOutput;
+
+/// The probability that an intersection stays in the same state from one frame to the next.
+const STICKINESS: f32 = 0.95;
+
+/// The smallest probability given to any state, so that the filter can always recover from a wrong belief.
+const MIN_PROBABILITY: f32 = 1e-4;
+
+/// The smallest likelihood that a single frame gives to any state, which limits how much one frame can change the belief.
+/// The model is often confident about a frame where the projector blinks, so together with the stickiness,
+/// this keeps a single frame from flipping a settled intersection, while a real change still goes through within three frames.
+const MIN_LIKELIHOOD: f32 = 0.05;
+
+/// Smooths the vision model's output over time with a hidden Markov model for each intersection.
+/// Each frame's probabilities are treated as evidence for the true state of the intersection,
+/// which is assumed to rarely change between frames.
+pub struct TemporalFilter {
+    beliefs: Vec<Vec<[f32; 4]>>,
+}
+
+impl TemporalFilter {
+    /// Creates a filter that doesn't know the state of any intersection yet.
+    pub fn new() -> Self {
+        Self { beliefs: vec![] }
+    }
+
+    /// Adds a frame of evidence and returns the filtered probabilities of each intersection.
+    pub fn update(
+        &mut self,
+        probabilities: &[Vec<VisionModelOutput>],
+    ) -> Vec<Vec<VisionModelOutput>> {
+        // Start over if the board size changed
+        let same_size = self.beliefs.len() == probabilities.len()
+            && self
+                .beliefs
+                .iter()
+                .zip(probabilities)
+                .all(|(a, b)| a.len() == b.len());
+        if !same_size {
+            self.beliefs = probabilities
+                .iter()
+                .map(|row| vec![[0.25; 4]; row.len()])
+                .collect();
+        }
+
+        for (beliefs, row) in self.beliefs.iter_mut().zip(probabilities) {
+            for (belief, &(empty, black, white, obscured)) in beliefs.iter_mut().zip(row) {
+                let evidence = [empty, black, white, obscured];
+                let mut total = 0.0;
+                for (state, likelihood) in evidence.iter().enumerate() {
+                    // Predict the state from the previous belief, then weigh it by the evidence
+                    let prior = belief[state] * STICKINESS
+                        + (1.0 - belief[state]) * (1.0 - STICKINESS) / 3.0;
+                    belief[state] = prior * likelihood.max(MIN_LIKELIHOOD);
+                    total += belief[state];
+                }
+                for probability in belief.iter_mut() {
+                    *probability = (*probability / total).max(MIN_PROBABILITY);
+                }
+            }
+        }
+
+        self.beliefs
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|&[empty, black, white, obscured]| (empty, black, white, obscured))
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::config::RecognitionConfig;
+
+    /// Runs the filter on a single intersection for the given number of frames of the same output.
+    fn run(
+        filter: &mut TemporalFilter,
+        output: VisionModelOutput,
+        frames: usize,
+    ) -> VisionModelOutput {
+        let mut filtered = vec![];
+        for _ in 0..frames {
+            filtered = filter.update(&[vec![output]]);
+        }
+        filtered[0][0]
+    }
+
+    #[test]
+    fn one_confident_frame_does_not_flip() {
+        let threshold = RecognitionConfig::default().black_threshold;
+        let mut filter = TemporalFilter::new();
+        run(&mut filter, (0.9997, 0.0001, 0.0001, 0.0001), 100);
+
+        let (_, black, _, _) = run(&mut filter, (0.0003, 0.999, 0.0004, 0.0003), 1);
+        assert!(black < threshold, "{}", black);
+        let (_, black, _, _) = run(&mut filter, (0.0003, 0.999, 0.0004, 0.0003), 2);
+        assert!(black > threshold, "{}", black);
+    }
+
+    #[test]
+    fn blink_is_ignored() {
+        let threshold = RecognitionConfig::default().black_threshold;
+        let mut filter = TemporalFilter::new();
+        run(&mut filter, (0.9997, 0.0001, 0.0001, 0.0001), 100);
+        run(&mut filter, (0.0003, 0.999, 0.0004, 0.0003), 1);
+        let (empty, black, _, _) = run(&mut filter, (0.9997, 0.0001, 0.0001, 0.0001), 1);
+        assert!(black < threshold, "{}", black);
+        assert!(
+            empty > RecognitionConfig::default().empty_threshold,
+            "{}",
+            empty
+        );
+    }
+}
@@ -34,20 +34,28 @@ impl GameState {
     /// Checks whether the user has made a move on the physical board, and if so,
     /// returns the corresponding action and a cooldown to wait before committing to the move.
     /// Otherwise, returns a list of incorrect coordinates.
+    /// If the hand that made the move was just seen leaving the board, the move is committed without a cooldown,
+    /// since the hand is no longer there to adjust the stones.
     pub fn check_for_move(
         &self,
         new_board: &Goban,
+        hand_left: bool,
     ) -> Result<(BoardUpdate, Color, u32), Vec<Coord>> {
         let user_turn = match self.game.turn() {
             Color::Black => self.user_black,
             Color::White => self.user_white,
         };
-        if let Some(pending_move) = self.pending_move {
+        let result = if let Some(pending_move) = self.pending_move {
             self.check_for_pending_move(new_board, pending_move)
         } else if user_turn {
             self.check_for_user_move(new_board)
         } else {
             Err(vec![])
+        };
+        if hand_left {
+            result.map(|(update, player, _)| (update, player, 0))
+        } else {
+            result
         }
     }
 
@@ -0,0 +1,470 @@
+use image::RgbImage;
+use imageproc::geometric_transformations::Projection;
+use saigo::STONE_SIZE;
+
+use super::config::{BoardConfig, CameraConfig, LensDistortion, Point};
+
+/// Maps points on the normalized board image to points on the camera frame.
+pub struct BoardMapping {
+    /// Maps from board image pixels to undistorted frame coordinates between 0 and 1.
+    projection: Projection,
+    lens: Lens,
+}
+
+impl BoardMapping {
+    /// Creates the mapping for the given configuration and camera frame size.
+    pub fn new(
+        camera: &CameraConfig,
+        board: &BoardConfig,
+        frame_width: u32,
+        frame_height: u32,
+    ) -> Self {
+        let lens = Lens::new(&camera.distortion, frame_width, frame_height);
+
+        // The control points are positions in the distorted frame, so remove the distortion first
+        let from = [
+            &camera.top_left,
+            &camera.top_right,
+            &camera.bottom_left,
+            &camera.bottom_right,
+        ]
+        .map(|point| {
+            let (x, y) = lens.undistort((point.x as f64, point.y as f64));
+            (x as f32, y as f32)
+        });
+        let to = board_corners(board);
+
+        // Transform from the 0-1 coordinate system to the final board image
+        let projection = Projection::from_control_points(from, to)
+            .map(|projection| projection.invert())
+            // If the control points are degenerate, map the frame directly onto the board image
+            .unwrap_or(Projection::scale(
+                1.0 / frame_width as f32,
+                1.0 / frame_height as f32,
+            ));
+
+        Self { projection, lens }
+    }
+
+    /// Maps a pixel position on the board image to a pixel position on the camera frame.
+    pub fn board_to_frame(&self, x: f32, y: f32) -> (f32, f32) {
+        let (u, v) = self.projection * (x, y);
+        let (u, v) = self.lens.distort((u as f64, v as f64));
+        ((u * self.lens.width) as f32, (v * self.lens.height) as f32)
+    }
+}
+
+/// A lookup table of where each pixel of the board image comes from in the camera frame.
+/// Building it evaluates the projection and lens model once, so that each frame only needs to be resampled.
+pub struct BoardRemap {
+    frame_width: u32,
+    frame_height: u32,
+    width: u32,
+    height: u32,
+    /// The sample for each pixel of the board image, or `None` if it falls outside the frame.
+    samples: Vec<Option<Sample>>,
+}
+
+/// The offsets of the four frame pixels that a board image pixel interpolates between, and their weights.
+struct Sample {
+    offsets: [u32; 4],
+    weights: [f32; 4],
+}
+
+impl BoardRemap {
+    /// Builds the lookup table for the given configuration and camera frame size.
+    pub fn new(
+        camera: &CameraConfig,
+        board: &BoardConfig,
+        frame_width: u32,
+        frame_height: u32,
+    ) -> Self {
+        let mapping = BoardMapping::new(camera, board, frame_width, frame_height);
+        let width = board.width.get() * STONE_SIZE;
+        let height = board.height.get() * STONE_SIZE;
+        let max_x = frame_width.saturating_sub(1);
+        let max_y = frame_height.saturating_sub(1);
+
+        let mut samples = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let (u, v) = mapping.board_to_frame(x as f32, y as f32);
+                if !(u >= 0.0 && v >= 0.0 && u <= max_x as f32 && v <= max_y as f32) {
+                    samples.push(None);
+                    continue;
+                }
+
+                // Interpolate bilinearly between the surrounding pixels
+                let (x0, y0) = (u.floor() as u32, v.floor() as u32);
+                let (x1, y1) = ((x0 + 1).min(max_x), (y0 + 1).min(max_y));
+                let (fx, fy) = (u - x0 as f32, v - y0 as f32);
+                let offset = |x: u32, y: u32| (y * frame_width + x) * 3;
+                samples.push(Some(Sample {
+                    offsets: [
+                        offset(x0, y0),
+                        offset(x1, y0),
+                        offset(x0, y1),
+                        offset(x1, y1),
+                    ],
+                    weights: [
+                        (1.0 - fx) * (1.0 - fy),
+                        fx * (1.0 - fy),
+                        (1.0 - fx) * fy,
+                        fx * fy,
+                    ],
+                }));
+            }
+        }
+
+        Self {
+            frame_width,
+            frame_height,
+            width,
+            height,
+            samples,
+        }
+    }
+
+    /// Checks whether the lookup table was built for frames of this size.
+    pub fn fits(&self, frame: &RgbImage) -> bool {
+        frame.dimensions() == (self.frame_width, self.frame_height)
+    }
+
+    /// Warps a camera frame to the normalized board image.
+    pub fn apply(&self, frame: &RgbImage) -> RgbImage {
+        let source = frame.as_raw();
+        let mut data = vec![0; (self.width * self.height * 3) as usize];
+        for (pixel, sample) in data.chunks_exact_mut(3).zip(&self.samples) {
+            let Some(sample) = sample else {
+                continue;
+            };
+            for (channel, value) in pixel.iter_mut().enumerate() {
+                *value = sample
+                    .offsets
+                    .iter()
+                    .zip(sample.weights)
+                    .map(|(&offset, weight)| source[offset as usize + channel] as f32 * weight)
+                    .sum::<f32>()
+                    .round() as u8;
+            }
+        }
+        RgbImage::from_raw(self.width, self.height, data).unwrap()
+    }
+}
+
+/// Returns the positions of the corner intersections on the board image,
+/// in the same order as the control points in the camera configuration.
+pub fn board_corners(board: &BoardConfig) -> [(f32, f32); 4] {
+    let right = STONE_SIZE as f32 * (board.width.get() as f32 - 0.5);
+    let bottom = STONE_SIZE as f32 * (board.height.get() as f32 - 0.5);
+    let half = STONE_SIZE as f32 * 0.5;
+    [(half, half), (right, half), (half, bottom), (right, bottom)]
+}
+
+/// A radial/tangential (Brown-Conrady) lens distortion model for a frame of a specific size.
+/// The model is evaluated in double precision, so that estimating the distortion can tell apart tiny changes to it.
+struct Lens {
+    /// The coefficients k1, k2, p1, and p2, in that order.
+    coefficients: [f64; 4],
+    width: f64,
+    height: f64,
+    /// Scales frame coordinates between 0 and 1 so that the longer axis spans -1 to 1,
+    /// keeping the distortion circular on non-square frames.
+    scale_x: f64,
+    scale_y: f64,
+}
+
+impl Lens {
+    fn new(distortion: &LensDistortion, width: u32, height: u32) -> Self {
+        let d = distortion;
+        Self::with_coefficients([d.k1, d.k2, d.p1, d.p2].map(|c| c as f64), width, height)
+    }
+
+    fn with_coefficients(coefficients: [f64; 4], width: u32, height: u32) -> Self {
+        let longest = width.max(height).max(1) as f64;
+        Self {
+            coefficients,
+            width: width as f64,
+            height: height as f64,
+            scale_x: 2.0 * width as f64 / longest,
+            scale_y: 2.0 * height as f64 / longest,
+        }
+    }
+
+    /// Applies the lens distortion to a point in frame coordinates between 0 and 1.
+    fn distort(&self, (u, v): (f64, f64)) -> (f64, f64) {
+        let x = (u - 0.5) * self.scale_x;
+        let y = (v - 0.5) * self.scale_y;
+        let (xd, yd) = distort_normalized(&self.coefficients, x, y);
+        (xd / self.scale_x + 0.5, yd / self.scale_y + 0.5)
+    }
+
+    /// Removes the lens distortion from a point in frame coordinates between 0 and 1.
+    fn undistort(&self, (u, v): (f64, f64)) -> (f64, f64) {
+        let xd = (u - 0.5) * self.scale_x;
+        let yd = (v - 0.5) * self.scale_y;
+        let (x, y) = undistort_normalized(&self.coefficients, xd, yd);
+        (x / self.scale_x + 0.5, y / self.scale_y + 0.5)
+    }
+}
+
+/// Applies the distortion model to a point centred on the optical axis.
+fn distort_normalized(&[k1, k2, p1, p2]: &[f64; 4], x: f64, y: f64) -> (f64, f64) {
+    let r2 = x * x + y * y;
+    let radial = 1.0 + k1 * r2 + k2 * r2 * r2;
+    (
+        x * radial + 2.0 * p1 * x * y + p2 * (r2 + 2.0 * x * x),
+        y * radial + p1 * (r2 + 2.0 * y * y) + 2.0 * p2 * x * y,
+    )
+}
+
+/// Inverts the distortion model by fixed-point iteration, since it has no closed form.
+fn undistort_normalized(&[k1, k2, p1, p2]: &[f64; 4], xd: f64, yd: f64) -> (f64, f64) {
+    let mut x = xd;
+    let mut y = yd;
+    for _ in 0..20 {
+        let r2 = x * x + y * y;
+        let radial = 1.0 + k1 * r2 + k2 * r2 * r2;
+        let dx = 2.0 * p1 * x * y + p2 * (r2 + 2.0 * x * x);
+        let dy = p1 * (r2 + 2.0 * y * y) + 2.0 * p2 * x * y;
+        x = (xd - dx) / radial;
+        y = (yd - dy) / radial;
+    }
+    (x, y)
+}
+
+/// Estimates the lens distortion from points clicked along the edges of the grid.
+/// Each point is assigned to the nearest edge of the outline formed by the corner points,
+/// and the distortion is chosen so that the points lie on straight lines once it is removed.
+pub fn estimate_distortion(
+    camera: &CameraConfig,
+    points: &[Point],
+    frame_width: u32,
+    frame_height: u32,
+) -> Option<LensDistortion> {
+    let corners = [
+        &camera.top_left,
+        &camera.top_right,
+        &camera.bottom_right,
+        &camera.bottom_left,
+    ]
+    .map(|point| (point.x as f64, point.y as f64));
+
+    // Assign each point to the nearest edge of the outline
+    let samples: Vec<((f64, f64), usize)> = points
+        .iter()
+        .map(|point| {
+            let point = (point.x as f64, point.y as f64);
+            let distance =
+                |edge: usize| segment_distance(point, corners[edge], corners[(edge + 1) % 4]);
+            let edge = (0..4)
+                .min_by(|&a, &b| distance(a).total_cmp(&distance(b)))
+                .unwrap();
+            (point, edge)
+        })
+        .collect();
+
+    // Calculates how far the points are from straight lines under the given distortion parameters
+    let residuals = |params: &[f64; 4]| -> Vec<f64> {
+        let lens = Lens::with_coefficients(*params, frame_width, frame_height);
+        let corners = corners.map(|corner| lens.undistort(corner));
+        let mut residuals: Vec<f64> = samples
+            .iter()
+            .map(|(point, edge)| {
+                line_distance(
+                    lens.undistort(*point),
+                    corners[*edge],
+                    corners[(edge + 1) % 4],
+                )
+            })
+            .collect();
+        // Keep the parameters small when the points don't constrain them well
+        residuals.extend(params.iter().map(|p| p * 0.01));
+        residuals
+    };
+
+    let params = least_squares(residuals, [0.0; 4], 50)?;
+    Some(LensDistortion {
+        k1: params[0] as f32,
+        k2: params[1] as f32,
+        p1: params[2] as f32,
+        p2: params[3] as f32,
+    })
+}
+
+/// Calculates the distance from a point to a line segment.
+fn segment_distance(p: (f64, f64), a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let length2 = dx * dx + dy * dy;
+    let t = if length2 > 0.0 {
+        (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / length2).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    let (cx, cy) = (a.0 + t * dx, a.1 + t * dy);
+    ((p.0 - cx).powi(2) + (p.1 - cy).powi(2)).sqrt()
+}
+
+/// Calculates the signed distance from a point to the infinite line through two points.
+fn line_distance(p: (f64, f64), a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let length = (dx * dx + dy * dy).sqrt();
+    if length > 0.0 {
+        ((p.0 - a.0) * dy - (p.1 - a.1) * dx) / length
+    } else {
+        0.0
+    }
+}
+
+/// Minimizes the sum of squared residuals using the Levenberg-Marquardt algorithm with a numerical Jacobian.
+pub fn least_squares<const N: usize>(
+    residuals: impl Fn(&[f64; N]) -> Vec<f64>,
+    initial: [f64; N],
+    iterations: usize,
+) -> Option<[f64; N]> {
+    const EPSILON: f64 = 1e-6;
+    let mut params = initial;
+    let mut current = residuals(&params);
+    let mut cost: f64 = current.iter().map(|r| r * r).sum();
+    let mut damping = 1e-3;
+
+    for _ in 0..iterations {
+        // Estimate the Jacobian by finite differences
+        let jacobian: Vec<Vec<f64>> = (0..N)
+            .map(|i| {
+                let mut shifted = params;
+                shifted[i] += EPSILON;
+                residuals(&shifted)
+                    .iter()
+                    .zip(&current)
+                    .map(|(r1, r0)| (r1 - r0) / EPSILON)
+                    .collect()
+            })
+            .collect();
+
+        // Build the damped normal equations
+        let mut a = vec![vec![0.0; N]; N];
+        let mut b = vec![0.0; N];
+        for i in 0..N {
+            for j in 0..N {
+                a[i][j] = jacobian[i]
+                    .iter()
+                    .zip(&jacobian[j])
+                    .map(|(x, y)| x * y)
+                    .sum();
+            }
+            a[i][i] += damping * a[i][i].max(1e-12);
+            b[i] = -jacobian[i]
+                .iter()
+                .zip(&current)
+                .map(|(x, r)| x * r)
+                .sum::<f64>();
+        }
+
+        let step = solve(a, b)?;
+        let mut candidate = params;
+        for i in 0..N {
+            candidate[i] += step[i];
+        }
+        let candidate_residuals = residuals(&candidate);
+        let candidate_cost: f64 = candidate_residuals.iter().map(|r| r * r).sum();
+        if candidate_cost < cost {
+            params = candidate;
+            current = candidate_residuals;
+            cost = candidate_cost;
+            damping *= 0.1;
+        } else {
+            damping *= 10.0;
+        }
+    }
+
+    Some(params)
+}
+
+/// Solves a system of linear equations using Gaussian elimination with partial pivoting.
+pub fn solve(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    for col in 0..n {
+        // Move the largest remaining value in this column onto the diagonal
+        let pivot = (col..n).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+
+        // Eliminate this column from the rows below
+        let pivot_row = a[col].clone();
+        for row in col + 1..n {
+            let factor = a[row][col] / pivot_row[col];
+            for (value, pivot_value) in a[row][col..].iter_mut().zip(&pivot_row[col..]) {
+                *value -= factor * pivot_value;
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+
+    // Back-substitute to find the solution
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let sum: f64 = (row + 1..n).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+    Some(x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates_known_distortion() {
+        let (width, height) = (640, 480);
+        let distortion = LensDistortion {
+            k1: -0.1,
+            k2: 0.0,
+            p1: 0.005,
+            p2: 0.0,
+        };
+        let lens = Lens::new(&distortion, width, height);
+
+        // The edges of the grid are straight before the distortion is applied
+        let corners = [(0.2, 0.15), (0.8, 0.2), (0.85, 0.85), (0.15, 0.8)];
+        let distorted = |(x, y): (f64, f64)| {
+            let (x, y) = lens.distort((x, y));
+            Point {
+                x: x as f32,
+                y: y as f32,
+            }
+        };
+        let mut camera = CameraConfig::default();
+        [
+            camera.top_left,
+            camera.top_right,
+            camera.bottom_right,
+            camera.bottom_left,
+        ] = corners.map(distorted);
+        let points: Vec<_> = (0..4)
+            .flat_map(|edge| {
+                let (a, b) = (corners[edge], corners[(edge + 1) % 4]);
+                (1..10).map(move |i| {
+                    let t = i as f64 / 10.0;
+                    (a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t)
+                })
+            })
+            .map(distorted)
+            .collect();
+
+        let estimate = estimate_distortion(&camera, &points, width, height).unwrap();
+        assert!(
+            (estimate.k1 - distortion.k1).abs() < 0.01,
+            "{}",
+            estimate.k1
+        );
+        assert!(
+            (estimate.p1 - distortion.p1).abs() < 0.001,
+            "{}",
+            estimate.p1
+        );
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::BTreeSet;
+
+use goban::pieces::util::coord::Coord;
+use serde::Serialize;
+
+/// How many frames after a hand leaves the board that a change to the board is still attributed to it.
+/// The smoothing over time can take a couple of frames to show the stone that the hand left behind.
+const RECENTLY_LEFT_FRAMES: u32 = 5;
+
+/// How many intersections a region must cover, and for how many frames in a row it must be seen,
+/// before it counts as a hand rather than a few misread intersections.
+const MIN_HAND_SIZE: usize = 2;
+const MIN_HAND_FRAMES: u32 = 3;
+
+/// A change to the hands or other objects covering the board.
+#[derive(Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HandEvent {
+    /// Something started covering part of the board.
+    Entered { id: u32, intersections: Vec<Coord> },
+    /// The part of the board covered by something changed.
+    Moved { id: u32, intersections: Vec<Coord> },
+    /// Something stopped covering the board.
+    Left { id: u32 },
+    /// Two things covering the board touched, and are followed as one from now on.
+    Merged { id: u32, into: u32 },
+}
+
+/// A connected region of obscured intersections, followed from frame to frame.
+struct HandRegion {
+    id: u32,
+    intersections: BTreeSet<Coord>,
+    /// The number of frames in a row that the region has been seen.
+    frames: u32,
+    /// Whether the region has been large enough for long enough to count as a hand.
+    /// Events are only sent for hands.
+    hand: bool,
+}
+
+/// Groups the obscured intersections into connected regions and follows them over time,
+/// so that a hand reaching over the board can be told apart from a single misread intersection.
+pub struct HandTracker {
+    regions: Vec<HandRegion>,
+    next_id: u32,
+    frames_since_left: Option<u32>,
+}
+
+impl HandTracker {
+    /// Creates a tracker with nothing on the board.
+    pub fn new() -> Self {
+        Self {
+            regions: vec![],
+            next_id: 0,
+            frames_since_left: None,
+        }
+    }
+
+    /// Updates the regions with the obscured intersections of a new frame, returning what changed.
+    /// A region keeps its identity as long as it overlaps the same region in the previous frame.
+    /// Nothing is sent for a region until it counts as a hand.
+    pub fn update(&mut self, obscured: &[Coord]) -> Vec<HandEvent> {
+        let mut events = vec![];
+        let mut previous = std::mem::take(&mut self.regions);
+        for intersections in connected_regions(obscured) {
+            let (mut overlapping, rest): (Vec<_>, Vec<_>) = previous
+                .into_iter()
+                .partition(|region| !region.intersections.is_disjoint(&intersections));
+            previous = rest;
+            // Follow the hand that has been seen the longest, and treat any others as having merged into it
+            let followed = (0..overlapping.len())
+                .max_by_key(|&i| (overlapping[i].hand, overlapping[i].frames))
+                .map(|i| overlapping.swap_remove(i));
+            let mut region = match followed {
+                Some(region) => {
+                    for merged in overlapping {
+                        if merged.hand {
+                            events.push(HandEvent::Merged {
+                                id: merged.id,
+                                into: region.id,
+                            });
+                        }
+                    }
+                    if region.hand && region.intersections != intersections {
+                        events.push(HandEvent::Moved {
+                            id: region.id,
+                            intersections: intersections.iter().copied().collect(),
+                        });
+                    }
+                    HandRegion {
+                        intersections,
+                        frames: region.frames.saturating_add(1),
+                        ..region
+                    }
+                }
+                None => {
+                    let id = self.next_id;
+                    self.next_id += 1;
+                    HandRegion {
+                        id,
+                        intersections,
+                        frames: 1,
+                        hand: false,
+                    }
+                }
+            };
+            if !region.hand
+                && region.frames >= MIN_HAND_FRAMES
+                && region.intersections.len() >= MIN_HAND_SIZE
+            {
+                region.hand = true;
+                events.push(HandEvent::Entered {
+                    id: region.id,
+                    intersections: region.intersections.iter().copied().collect(),
+                });
+            }
+            self.regions.push(region);
+        }
+        for region in previous {
+            if region.hand {
+                events.push(HandEvent::Left { id: region.id });
+            }
+        }
+
+        // Remember when the board was last left clear of hands
+        let left = events
+            .iter()
+            .any(|event| matches!(event, HandEvent::Left { .. }));
+        self.frames_since_left = if self.regions.iter().any(|region| region.hand) {
+            None
+        } else if left {
+            Some(0)
+        } else {
+            self.frames_since_left
+                .map(|frames| frames.saturating_add(1))
+        };
+        events
+    }
+
+    /// Returns whether the last hand left the board in the last few frames, and nothing has covered it since.
+    pub fn hand_left_recently(&self) -> bool {
+        self.frames_since_left
+            .is_some_and(|frames| frames < RECENTLY_LEFT_FRAMES)
+    }
+}
+
+/// Groups intersections into regions of horizontally, vertically or diagonally adjacent intersections.
+fn connected_regions(intersections: &[Coord]) -> Vec<BTreeSet<Coord>> {
+    let mut remaining: BTreeSet<Coord> = intersections.iter().copied().collect();
+    let mut regions = vec![];
+    while let Some(start) = remaining.pop_first() {
+        let mut region = BTreeSet::from([start]);
+        let mut frontier = vec![start];
+        while let Some((x, y)) = frontier.pop() {
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    let neighbour = (
+                        (x as i16 + dx).clamp(0, u8::MAX as i16) as u8,
+                        (y as i16 + dy).clamp(0, u8::MAX as i16) as u8,
+                    );
+                    if remaining.remove(&neighbour) {
+                        region.insert(neighbour);
+                        frontier.push(neighbour);
+                    }
+                }
+            }
+        }
+        regions.push(region);
+    }
+    regions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the tracker on the same obscured intersections for the given number of frames, returning all events.
+    fn run(tracker: &mut HandTracker, obscured: &[Coord], frames: usize) -> Vec<HandEvent> {
+        (0..frames).flat_map(|_| tracker.update(obscured)).collect()
+    }
+
+    /// Returns the id of the only hand that entered.
+    fn entered(events: &[HandEvent]) -> u32 {
+        match events {
+            [HandEvent::Entered { id, .. }] => *id,
+            _ => panic!("Expected a single hand to enter"),
+        }
+    }
+
+    #[test]
+    fn small_or_brief_regions_are_not_hands() {
+        let mut tracker = HandTracker::new();
+        assert!(run(&mut tracker, &[(3, 3)], 10).is_empty());
+        assert!(run(&mut tracker, &[], 1).is_empty());
+
+        let mut tracker = HandTracker::new();
+        let hand = [(3, 3), (3, 4)];
+        assert!(run(&mut tracker, &hand, MIN_HAND_FRAMES as usize - 1).is_empty());
+        assert!(run(&mut tracker, &[], 1).is_empty());
+        assert!(!tracker.hand_left_recently());
+
+        let mut tracker = HandTracker::new();
+        run(&mut tracker, &hand, MIN_HAND_FRAMES as usize - 1);
+        entered(&run(&mut tracker, &hand, 1));
+    }
+
+    #[test]
+    fn moving_hand_keeps_its_identity() {
+        let mut tracker = HandTracker::new();
+        let id = entered(&run(
+            &mut tracker,
+            &[(3, 3), (3, 4)],
+            MIN_HAND_FRAMES as usize,
+        ));
+        let events = tracker.update(&[(3, 4), (3, 5), (4, 5)]);
+        assert!(matches!(events[..], [HandEvent::Moved { id: moved, .. }] if moved == id));
+        let events = tracker.update(&[(4, 5), (5, 6)]);
+        assert!(matches!(events[..], [HandEvent::Moved { id: moved, .. }] if moved == id));
+        let events = tracker.update(&[]);
+        assert!(matches!(events[..], [HandEvent::Left { id: left }] if left == id));
+    }
+
+    #[test]
+    fn merging_hands_are_not_reported_as_leaving() {
+        let mut tracker = HandTracker::new();
+        let events = run(
+            &mut tracker,
+            &[(0, 0), (0, 1), (5, 0), (5, 1)],
+            MIN_HAND_FRAMES as usize,
+        );
+        assert_eq!(events.len(), 2);
+
+        let joined = [
+            (0, 0),
+            (0, 1),
+            (1, 1),
+            (2, 1),
+            (3, 1),
+            (4, 1),
+            (5, 0),
+            (5, 1),
+        ];
+        let events = tracker.update(&joined);
+        assert!(
+            !events
+                .iter()
+                .any(|event| matches!(event, HandEvent::Left { .. }))
+        );
+        let merged: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                HandEvent::Merged { id, into } => Some((*id, *into)),
+                _ => None,
+            })
+            .collect();
+        let [(id, into)] = merged[..] else {
+            panic!("Expected one hand to merge into the other");
+        };
+        assert_ne!(id, into);
+        assert!(
+            events
+                .iter()
+                .any(|event| matches!(event, HandEvent::Moved { id, .. } if *id == into))
+        );
+
+        let events = tracker.update(&[]);
+        assert!(matches!(events[..], [HandEvent::Left { id }] if id == into));
+    }
+
+    #[test]
+    fn hand_left_recently_expires() {
+        let mut tracker = HandTracker::new();
+        run(&mut tracker, &[(3, 3), (3, 4)], MIN_HAND_FRAMES as usize);
+        assert!(!tracker.hand_left_recently());
+        tracker.update(&[]);
+        for _ in 0..RECENTLY_LEFT_FRAMES {
+            assert!(tracker.hand_left_recently());
+            tracker.update(&[]);
+        }
+        assert!(!tracker.hand_left_recently());
+    }
+}
@@ -0,0 +1,161 @@
+#[cfg(feature = "tch")]
+use std::fs;
+use std::{collections::BTreeMap, path::Path};
+
+#[cfg(feature = "onnx")]
+use saigo::onnx::OnnxRecognizer;
+use saigo::recognizer::{BoardRecognizer, DifferenceRecognizer, RecognizerKind};
+#[cfg(feature = "tch")]
+use saigo::{
+    model_file::{check_metadata, read_metadata},
+    vision_model::VisionModelRecognizer,
+};
+use serde::Serialize;
+
+use super::{config::ModelConfig, motion::InferenceStats};
+use crate::error::SaigoError;
+
+/// The file extension of models exported to ONNX.
+const ONNX_EXTENSION: &str = "onnx";
+
+/// A board recognizer that is ready to use, along with a description of it.
+pub struct LoadedModel {
+    pub recognizer: Box<dyn BoardRecognizer>,
+    pub info: ModelInfo,
+}
+
+/// A description of a loaded model.
+#[derive(Clone, Serialize)]
+pub struct ModelInfo {
+    /// The kind of recognizer.
+    pub recognizer: RecognizerKind,
+    /// The path the model was loaded from, if it was loaded from a file.
+    pub path: Option<String>,
+    /// The number of trained parameters in the model.
+    pub parameters: i64,
+    /// The metadata stored in the header of the model file.
+    pub metadata: BTreeMap<String, String>,
+    /// The training notes saved next to the model file, if there are any.
+    pub notes: Option<String>,
+}
+
+/// The configured model and whether it could be loaded.
+#[derive(Clone, Serialize)]
+pub struct ModelStatus {
+    /// The configured recognizer.
+    pub recognizer: RecognizerKind,
+    /// The path of the configured model.
+    pub path: String,
+    /// The model that is currently in use.
+    pub model: Option<ModelInfo>,
+    /// The error from loading the configured model, if it failed.
+    pub error: Option<String>,
+    /// The path of the configured fallback model.
+    pub fallback_path: Option<String>,
+    /// The fallback model that is currently in use.
+    pub fallback_model: Option<ModelInfo>,
+    /// The error from loading the configured fallback model, if it failed.
+    pub fallback_error: Option<String>,
+    /// How much of the board the model had to recognize.
+    pub inference: InferenceStats,
+}
+
+impl LoadedModel {
+    /// Creates the configured recognizer, loading and checking the model file if it needs one.
+    pub fn load(config: &ModelConfig) -> Result<Self, SaigoError> {
+        match config.recognizer {
+            RecognizerKind::Model => Self::load_vision_model(&config.path),
+            RecognizerKind::Difference => Ok(Self {
+                recognizer: Box::new(DifferenceRecognizer),
+                info: ModelInfo {
+                    recognizer: RecognizerKind::Difference,
+                    path: None,
+                    parameters: 0,
+                    metadata: BTreeMap::new(),
+                    notes: None,
+                },
+            }),
+        }
+    }
+
+    /// Loads a model that can run without a reference image, to fall back to until one is taken.
+    pub fn load_fallback(path: &str) -> Result<Self, SaigoError> {
+        let model = Self::load_vision_model(path)?;
+        if model.recognizer.needs_reference() {
+            return Err(SaigoError::InvalidModel(format!(
+                "{}: The fallback model must not read the reference image.",
+                path
+            )));
+        }
+        Ok(model)
+    }
+
+    /// Loads the neural network vision model from a file, which can be in safetensors or ONNX format.
+    fn load_vision_model(path: &str) -> Result<Self, SaigoError> {
+        let is_onnx = Path::new(path)
+            .extension()
+            .is_some_and(|extension| extension == ONNX_EXTENSION);
+        if is_onnx {
+            Self::load_onnx_model(path)
+        } else {
+            Self::load_libtorch_model(path)
+        }
+    }
+
+    /// Loads a vision model in safetensors format, which runs with LibTorch.
+    #[cfg(feature = "tch")]
+    fn load_libtorch_model(path: &str) -> Result<Self, SaigoError> {
+        let invalid = |message: String| SaigoError::InvalidModel(format!("{}: {}", path, message));
+
+        let metadata = read_metadata(Path::new(path)).map_err(|e| invalid(e.to_string()))?;
+        let format = check_metadata(&metadata).map_err(invalid)?;
+        let recognizer = VisionModelRecognizer::load(Path::new(path), format)
+            .map_err(|e| invalid(e.to_string()))?;
+        let notes = fs::read_to_string(Path::new(path).with_extension("txt")).ok();
+        Ok(Self {
+            info: ModelInfo {
+                recognizer: RecognizerKind::Model,
+                path: Some(path.to_string()),
+                parameters: recognizer.parameters(),
+                metadata,
+                notes,
+            },
+            recognizer: Box::new(recognizer),
+        })
+    }
+
+    /// Reports that models in safetensors format aren't available in this build.
+    #[cfg(not(feature = "tch"))]
+    fn load_libtorch_model(path: &str) -> Result<Self, SaigoError> {
+        Err(SaigoError::InvalidModel(format!(
+            "{}: This build of Saigo doesn't include LibTorch, so it can only load models exported to ONNX.",
+            path
+        )))
+    }
+
+    /// Loads a vision model in ONNX format, which runs without LibTorch.
+    #[cfg(feature = "onnx")]
+    fn load_onnx_model(path: &str) -> Result<Self, SaigoError> {
+        let recognizer = OnnxRecognizer::load(Path::new(path))
+            .map_err(|e| SaigoError::InvalidModel(format!("{}: {}", path, e)))?;
+        Ok(Self {
+            info: ModelInfo {
+                recognizer: RecognizerKind::Model,
+                path: Some(path.to_string()),
+                parameters: recognizer.parameters(),
+                metadata: recognizer.metadata().clone(),
+                notes: None,
+            },
+            recognizer: Box::new(recognizer),
+        })
+    }
+
+    /// Reports that models in ONNX format aren't available in this build.
+    #[cfg(not(feature = "onnx"))]
+    fn load_onnx_model(path: &str) -> Result<Self, SaigoError> {
+        Err(SaigoError::InvalidModel(format!(
+            "{}: This build of Saigo doesn't support models in ONNX format.",
+            path
+        )))
+    }
+}
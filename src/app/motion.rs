@@ -0,0 +1,155 @@
+use std::time::{Duration, Instant};
+
+use image::Rgb32FImage;
+use saigo::{
+    STONE_SIZE,
+    recognizer::{BoardRecognizer, VisionModelOutput},
+};
+use serde::Serialize;
+
+/// How much the average color of an intersection must change before it is recognized again.
+/// This is well above the noise of a typical camera, but below the change from a stone or a hand.
+const MOTION_THRESHOLD: f32 = 0.03;
+
+/// The largest fraction of the board that is recognized by itself, beyond which the whole board is recognized.
+const MAX_PARTIAL_FRACTION: f32 = 0.5;
+
+/// How often the whole board is recognized even if nothing seems to change, to catch slow changes in lighting.
+const FULL_INFERENCE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Counts how much of the board the vision loop had to recognize.
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct InferenceStats {
+    /// The number of frames processed since the server started.
+    pub frames: u64,
+    /// The number of frames where nothing changed, so the previous output was reused.
+    pub skipped_frames: u64,
+    /// The number of frames where only the intersections that changed were recognized again.
+    pub partial_frames: u64,
+}
+
+/// Only recognizes the intersections that changed since they were last recognized, reusing the output of the rest.
+/// Each intersection is compared to the images it was last recognized from, so that slow changes add up.
+pub struct MotionGate {
+    /// The board image, reference image, and display image that each intersection was last recognized from.
+    recognized: Option<[Rgb32FImage; 3]>,
+    /// The output for each intersection when it was last recognized.
+    outputs: Vec<Vec<VisionModelOutput>>,
+    last_full_inference: Instant,
+    stats: InferenceStats,
+}
+
+impl MotionGate {
+    /// Creates a gate that recognizes the whole board in the first frame.
+    pub fn new() -> Self {
+        Self {
+            recognized: None,
+            outputs: vec![],
+            last_full_inference: Instant::now(),
+            stats: InferenceStats::default(),
+        }
+    }
+
+    /// Forgets the previous output, so that the whole board is recognized in the next frame.
+    pub fn invalidate(&mut self) {
+        self.recognized = None;
+    }
+
+    /// Returns the counts of frames that were skipped or partially recognized.
+    pub fn stats(&self) -> InferenceStats {
+        self.stats
+    }
+
+    /// Returns the probabilities for each intersection, running the recognizer on as little of the board as possible.
+    /// If the recognizer fails, the whole board is recognized again in the next frame.
+    pub fn recognize(
+        &mut self,
+        recognizer: &dyn BoardRecognizer,
+        image: &Rgb32FImage,
+        reference: &Rgb32FImage,
+        display: &Rgb32FImage,
+    ) -> Result<Vec<Vec<VisionModelOutput>>, String> {
+        self.stats.frames += 1;
+        let images = [image, reference, display];
+        match self.changed_intersections(images) {
+            Some(changed) if changed.is_empty() => {
+                self.stats.skipped_frames += 1;
+            }
+            // Whole-board models recognize the whole board anyway, so the whole board is brought up to date
+            Some(changed) if recognizer.recognizes_intersections_separately() => {
+                self.stats.partial_frames += 1;
+                let outputs = recognizer
+                    .recognize_intersections(image, reference, display, &changed)
+                    .inspect_err(|_| self.invalidate())?;
+                let recognized = self.recognized.as_mut().unwrap();
+                for (&(x, y), output) in changed.iter().zip(outputs) {
+                    self.outputs[y as usize][x as usize] = output;
+                    for (from, to) in images.iter().zip(recognized.iter_mut()) {
+                        copy_intersection(from, to, x, y);
+                    }
+                }
+            }
+            _ => {
+                self.outputs = recognizer
+                    .recognize(image, reference, display)
+                    .inspect_err(|_| self.invalidate())?;
+                self.recognized = Some(images.map(|image| image.clone()));
+                self.last_full_inference = Instant::now();
+            }
+        }
+        Ok(self.outputs.clone())
+    }
+
+    /// Finds the intersections that changed since they were last recognized,
+    /// or returns None if the whole board has to be recognized.
+    fn changed_intersections(&self, images: [&Rgb32FImage; 3]) -> Option<Vec<(u32, u32)>> {
+        let recognized = self.recognized.as_ref()?;
+        if images
+            .iter()
+            .zip(recognized)
+            .any(|(image, recognized)| image.dimensions() != recognized.dimensions())
+            || self.last_full_inference.elapsed() >= FULL_INFERENCE_INTERVAL
+        {
+            return None;
+        }
+
+        let width = images[0].width() / STONE_SIZE;
+        let height = images[0].height() / STONE_SIZE;
+        let changed: Vec<_> = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .filter(|&(x, y)| {
+                // Changes in the projected light or in the adapted reference image
+                // can change how the model sees the intersection too
+                images.iter().zip(recognized).any(|(image, recognized)| {
+                    intersection_difference(image, recognized, x, y) > MOTION_THRESHOLD
+                })
+            })
+            .collect();
+        if changed.len() as f32 > (width * height) as f32 * MAX_PARTIAL_FRACTION {
+            return None;
+        }
+        Some(changed)
+    }
+}
+
+/// Calculates the average difference in color between two images over the stone-sized area of an intersection.
+fn intersection_difference(a: &Rgb32FImage, b: &Rgb32FImage, x: u32, y: u32) -> f32 {
+    let mut difference = 0.0;
+    for py in y * STONE_SIZE..(y + 1) * STONE_SIZE {
+        for px in x * STONE_SIZE..(x + 1) * STONE_SIZE {
+            let pa = a.get_pixel(px, py);
+            let pb = b.get_pixel(px, py);
+            difference += (0..3).map(|c| (pa[c] - pb[c]).abs()).sum::<f32>() / 3.0;
+        }
+    }
+    difference / (STONE_SIZE * STONE_SIZE) as f32
+}
+
+/// Copies the stone-sized area of an intersection from one image to another.
+fn copy_intersection(from: &Rgb32FImage, to: &mut Rgb32FImage, x: u32, y: u32) {
+    for py in y * STONE_SIZE..(y + 1) * STONE_SIZE {
+        for px in x * STONE_SIZE..(x + 1) * STONE_SIZE {
+            to.put_pixel(px, py, *from.get_pixel(px, py));
+        }
+    }
+}
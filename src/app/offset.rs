@@ -0,0 +1,103 @@
+use image::Rgb32FImage;
+use saigo::{
+    STONE_SIZE,
+    recognizer::{MAX_DISPLAY_BRIGHTNESS, VisionModelOutput, luminance},
+};
+
+/// How likely an intersection must be to contain a stone before its offset is estimated.
+const MIN_STONE_PROBABILITY: f32 = 0.5;
+
+/// The difference from the reference image at which a pixel starts to count as part of a stone,
+/// and the additional difference at which it fully counts.
+const MIN_DIFFERENCE: f32 = 0.04;
+const FULL_DIFFERENCE: f32 = 0.08;
+
+/// The fraction of the disc around an intersection that must be unlit by the display for its offset to be estimated.
+const MIN_UNLIT_FRACTION: f32 = 0.5;
+
+/// Estimates how far the stone on each intersection is from the center of the intersection,
+/// in stone widths to the right and down, or zero where there is no stone.
+/// This is a heuristic based on the difference to the reference image, not an output of the vision model.
+///
+/// Only the stone-sized disc around the intersection is considered, so neighbouring stones stay out of it.
+/// A stone with the same size as the disc covers a lens-shaped area that is symmetric around the point
+/// halfway between the intersection and the center of the stone, so the offset is twice the centroid of the covered area.
+///
+/// Pixels lit by the display are left out, since projected light changes the color just like a stone does.
+/// Intersections that are mostly lit keep their previous offset, so that blinking highlights don't make it flicker.
+pub fn estimate_stone_offsets(
+    image: &Rgb32FImage,
+    reference: &Rgb32FImage,
+    display: &Rgb32FImage,
+    probabilities: &[Vec<VisionModelOutput>],
+    previous: &[Vec<(f32, f32)>],
+) -> Vec<Vec<(f32, f32)>> {
+    probabilities
+        .iter()
+        .enumerate()
+        .map(|(y, row)| {
+            row.iter()
+                .enumerate()
+                .map(|(x, p)| {
+                    if p.1 + p.2 < MIN_STONE_PROBABILITY {
+                        return (0.0, 0.0);
+                    }
+                    estimate_offset(image, reference, display, x as u32, y as u32)
+                        .or_else(|| previous.get(y).and_then(|row| row.get(x)).copied())
+                        .unwrap_or((0.0, 0.0))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Estimates the offset of the stone on the intersection with the given coordinates,
+/// or returns None if too much of the intersection is lit by the display.
+fn estimate_offset(
+    image: &Rgb32FImage,
+    reference: &Rgb32FImage,
+    display: &Rgb32FImage,
+    x: u32,
+    y: u32,
+) -> Option<(f32, f32)> {
+    let center = (STONE_SIZE as f32 - 1.0) * 0.5;
+    let radius = STONE_SIZE as f32 * 0.5;
+    let mut disc = 0.0;
+    let mut unlit = 0.0;
+    let mut total = 0.0;
+    let mut sum_x = 0.0;
+    let mut sum_y = 0.0;
+    for py in 0..STONE_SIZE {
+        for px in 0..STONE_SIZE {
+            let dx = px as f32 - center;
+            let dy = py as f32 - center;
+            if dx * dx + dy * dy > radius * radius {
+                continue;
+            }
+            disc += 1.0;
+            if luminance(display.get_pixel(x * STONE_SIZE + px, y * STONE_SIZE + py))
+                > MAX_DISPLAY_BRIGHTNESS
+            {
+                continue;
+            }
+            unlit += 1.0;
+            let pixel = image.get_pixel(x * STONE_SIZE + px, y * STONE_SIZE + py);
+            let ref_pixel = reference.get_pixel(x * STONE_SIZE + px, y * STONE_SIZE + py);
+            let difference = (0..3).map(|c| (pixel[c] - ref_pixel[c]).abs()).sum::<f32>() / 3.0;
+            let weight = ((difference - MIN_DIFFERENCE) / FULL_DIFFERENCE).clamp(0.0, 1.0);
+            total += weight;
+            sum_x += weight * dx;
+            sum_y += weight * dy;
+        }
+    }
+    if unlit < disc * MIN_UNLIT_FRACTION {
+        return None;
+    }
+    if total == 0.0 {
+        return Some((0.0, 0.0));
+    }
+    Some((
+        2.0 * sum_x / total / STONE_SIZE as f32,
+        2.0 * sum_y / total / STONE_SIZE as f32,
+    ))
+}
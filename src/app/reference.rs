@@ -0,0 +1,167 @@
+use image::{Rgb32FImage, RgbImage};
+use saigo::STONE_SIZE;
+use serde::Serialize;
+
+use super::VisionModelOutput;
+use crate::error::SaigoError;
+
+/// How different a stone-sized area of a frame can be from the combined image before it counts as motion,
+/// as the mean absolute difference in levels from 0 to 255.
+const MAX_MOTION: f32 = 12.0;
+
+/// The fraction of the frames in which a stone-sized area can differ from the combined image before it counts as motion.
+/// The median leaves out a brief flicker in a few frames, but a hand over the board stays for longer.
+const MAX_MOVING_FRACTION: f32 = 0.25;
+
+/// How confident the vision model must be that an intersection is empty before the reference image follows it.
+const MIN_EMPTY_PROBABILITY: f32 = 0.98;
+
+/// How much of the current board image is blended into the reference image on each frame.
+/// At 10 frames per second, this follows a change in lighting over roughly a minute.
+const ADAPTATION_RATE: f32 = 0.005;
+
+/// Measurements of how suitable a captured reference image is.
+#[derive(Clone, Serialize)]
+pub struct ReferenceQuality {
+    /// The number of frames that were combined.
+    pub frames: usize,
+    /// The average standard deviation of each pixel across the frames, in levels from 0 to 255.
+    pub noise: f32,
+    /// The brightness of the darkest part of the board relative to the brightest, from 0 to 1.
+    pub uniformity: f32,
+}
+
+/// Combines several board images into a reference image by taking the median of each pixel,
+/// so that sensor noise and brief flickers of the display don't end up in the reference.
+/// Fails if anything moved over the board for more than a few of the frames.
+pub fn combine_frames(frames: &[RgbImage]) -> Result<(RgbImage, ReferenceQuality), SaigoError> {
+    let Some(first) = frames.first() else {
+        return Err(SaigoError::CameraUnavailable(
+            "No frames were captured from the camera.".to_string(),
+        ));
+    };
+    let (width, height) = first.dimensions();
+    if frames
+        .iter()
+        .any(|frame| frame.dimensions() != (width, height))
+    {
+        return Err(SaigoError::InvalidCalibration(
+            "The board size changed while capturing the reference image.".to_string(),
+        ));
+    }
+
+    // Take the median of each channel of each pixel, and measure how much it varies between frames
+    let mut combined = RgbImage::new(width, height);
+    let mut total_deviation = 0.0;
+    let mut values = Vec::with_capacity(frames.len());
+    for (x, y, pixel) in combined.enumerate_pixels_mut() {
+        for channel in 0..3 {
+            values.clear();
+            values.extend(frames.iter().map(|frame| frame.get_pixel(x, y)[channel]));
+            values.sort_unstable();
+            pixel[channel] = values[values.len() / 2];
+
+            let mean = values.iter().map(|&value| value as f32).sum::<f32>() / values.len() as f32;
+            let variance = values
+                .iter()
+                .map(|&value| (value as f32 - mean).powi(2))
+                .sum::<f32>()
+                / values.len() as f32;
+            total_deviation += variance.sqrt();
+        }
+    }
+    let noise = total_deviation / (width * height * 3).max(1) as f32;
+
+    // Compare each intersection in each frame to the combined image to find anything that moved,
+    // since a hand passing over the board would be too large to be noise
+    let cell_size = (STONE_SIZE * STONE_SIZE * 3) as f32;
+    let mut brightness = vec![];
+    for top in (0..height / STONE_SIZE).map(|row| row * STONE_SIZE) {
+        for left in (0..width / STONE_SIZE).map(|column| column * STONE_SIZE) {
+            let cell = || {
+                (top..top + STONE_SIZE)
+                    .flat_map(move |y| (left..left + STONE_SIZE).map(move |x| (x, y)))
+            };
+            let moving_frames = frames
+                .iter()
+                .filter(|frame| {
+                    let difference = cell()
+                        .map(|(x, y)| {
+                            let a = frame.get_pixel(x, y);
+                            let b = combined.get_pixel(x, y);
+                            (0..3).map(|c| a[c].abs_diff(b[c]) as f32).sum::<f32>()
+                        })
+                        .sum::<f32>()
+                        / cell_size;
+                    difference > MAX_MOTION
+                })
+                .count();
+            if moving_frames as f32 > frames.len() as f32 * MAX_MOVING_FRACTION {
+                return Err(SaigoError::InvalidCalibration(
+                        "Something moved over the board while capturing the reference image. Keep the board clear and try again."
+                            .to_string(),
+                    ));
+            }
+            brightness.push(
+                cell()
+                    .map(|(x, y)| {
+                        combined
+                            .get_pixel(x, y)
+                            .0
+                            .iter()
+                            .map(|&c| c as f32)
+                            .sum::<f32>()
+                    })
+                    .sum::<f32>()
+                    / cell_size,
+            );
+        }
+    }
+
+    let brightest = brightness.iter().copied().fold(0.0, f32::max);
+    let darkest = brightness.iter().copied().fold(f32::INFINITY, f32::min);
+    let uniformity = if brightest > 0.0 {
+        darkest / brightest
+    } else {
+        0.0
+    };
+
+    Ok((
+        combined,
+        ReferenceQuality {
+            frames: frames.len(),
+            noise,
+            uniformity,
+        },
+    ))
+}
+
+/// Slowly blends the current board image into the reference image at the intersections that are confidently empty,
+/// so that the reference follows gradual changes in lighting.
+pub fn adapt_reference(
+    reference: &mut Rgb32FImage,
+    image: &Rgb32FImage,
+    probabilities: &[Vec<VisionModelOutput>],
+) {
+    if reference.dimensions() != image.dimensions() {
+        return;
+    }
+    for (y, row) in probabilities.iter().enumerate() {
+        for (x, (empty, _, _, _)) in row.iter().enumerate() {
+            if *empty < MIN_EMPTY_PROBABILITY {
+                continue;
+            }
+            let left = x as u32 * STONE_SIZE;
+            let top = y as u32 * STONE_SIZE;
+            for py in top..top + STONE_SIZE {
+                for px in left..left + STONE_SIZE {
+                    let current = image.get_pixel(px, py);
+                    let pixel = reference.get_pixel_mut(px, py);
+                    for channel in 0..3 {
+                        pixel[channel] += (current[channel] - pixel[channel]) * ADAPTATION_RATE;
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use image::{GrayImage, RgbImage, buffer::ConvertBuffer};
+use imageproc::geometric_transformations::Projection;
+use saigo::STONE_SIZE;
+
+use super::{config::BoardConfig, geometry::solve};
+
+/// How far to search for each intersection, in pixels of the board image.
+/// This stays below half a stone so that neighbouring intersections can't be confused.
+const SEARCH_RADIUS: i32 = STONE_SIZE as i32 / 2 - 2;
+
+/// The minimum normalized cross-correlation for an intersection to count as matched.
+const MIN_CORRELATION: f32 = 0.7;
+
+/// The minimum standard deviation of a reference patch for it to be useful for matching.
+const MIN_CONTRAST: f32 = 8.0;
+
+/// A point in the reference image and the corresponding point in the current board image.
+type Match = ((f32, f32), (f32, f32));
+
+/// How the grid in the current board image is aligned relative to the reference image.
+pub struct Alignment {
+    /// Maps positions in the reference image to where they currently appear in the board image.
+    pub correction: Projection,
+    /// How far the corners of the grid have moved, in pixels of the board image.
+    pub drift: f32,
+}
+
+/// Matches the intersections of the reference image against the current board image,
+/// and fits a projection that describes how the grid has moved.
+/// Returns `None` if too few intersections could be matched, for example because the board moved too far.
+pub fn track_board(
+    reference: &RgbImage,
+    current: &RgbImage,
+    board: &BoardConfig,
+) -> Option<Alignment> {
+    if reference.dimensions() != current.dimensions() {
+        return None;
+    }
+    let reference: GrayImage = reference.convert();
+    let current: GrayImage = current.convert();
+    let width = board.width.get();
+    let height = board.height.get();
+
+    // Find where each intersection appears in the current image
+    let mut matches = vec![];
+    for y in 0..height {
+        for x in 0..width {
+            let left = x * STONE_SIZE;
+            let top = y * STONE_SIZE;
+            if let Some((dx, dy)) = match_patch(&reference, &current, left, top) {
+                let cx = (left + STONE_SIZE / 2) as f32;
+                let cy = (top + STONE_SIZE / 2) as f32;
+                matches.push(((cx, cy), (cx + dx as f32, cy + dy as f32)));
+            }
+        }
+    }
+
+    // Intersections covered by stones or hands won't match, but there should be enough empty ones
+    let required = ((width * height) as usize / 8).max(8);
+    if matches.len() < required {
+        return None;
+    }
+
+    // Fit the projection, then refit without the matches that disagree with it
+    let correction = fit_projection(&matches)?;
+    let inliers: Vec<_> = matches
+        .into_iter()
+        .filter(|&(from, to)| distance(correction * from, to) < 1.5)
+        .collect();
+    if inliers.len() < required {
+        return None;
+    }
+    let correction = fit_projection(&inliers)?;
+
+    let right = (width * STONE_SIZE) as f32;
+    let bottom = (height * STONE_SIZE) as f32;
+    let drift = [(0.0, 0.0), (right, 0.0), (0.0, bottom), (right, bottom)]
+        .into_iter()
+        .map(|corner| distance(correction * corner, corner))
+        .fold(0.0, f32::max);
+
+    Some(Alignment { correction, drift })
+}
+
+/// Finds the offset at which a stone-sized patch of the reference image best matches the current image.
+fn match_patch(
+    reference: &GrayImage,
+    current: &GrayImage,
+    left: u32,
+    top: u32,
+) -> Option<(i32, i32)> {
+    let size = STONE_SIZE as i32;
+    let patch: Vec<f32> = (0..STONE_SIZE)
+        .flat_map(|y| (0..STONE_SIZE).map(move |x| (x, y)))
+        .map(|(x, y)| reference.get_pixel(left + x, top + y).0[0] as f32)
+        .collect();
+    let mean = patch.iter().sum::<f32>() / patch.len() as f32;
+    let patch: Vec<f32> = patch.iter().map(|value| value - mean).collect();
+    let norm = patch.iter().map(|value| value * value).sum::<f32>().sqrt();
+
+    // Featureless patches, such as the margin of the board, can't be located reliably
+    if norm / (patch.len() as f32).sqrt() < MIN_CONTRAST {
+        return None;
+    }
+
+    let (image_width, image_height) = current.dimensions();
+    let mut best = None;
+    let mut best_correlation = MIN_CORRELATION;
+    let mut window = Vec::with_capacity(patch.len());
+    for dy in -SEARCH_RADIUS..=SEARCH_RADIUS {
+        for dx in -SEARCH_RADIUS..=SEARCH_RADIUS {
+            let x0 = left as i32 + dx;
+            let y0 = top as i32 + dy;
+            if x0 < 0 || y0 < 0 || x0 + size > image_width as i32 || y0 + size > image_height as i32
+            {
+                continue;
+            }
+
+            window.clear();
+            window.extend(
+                (0..size)
+                    .flat_map(|y| (0..size).map(move |x| (x, y)))
+                    .map(|(x, y)| current.get_pixel((x0 + x) as u32, (y0 + y) as u32).0[0] as f32),
+            );
+            let window_mean = window.iter().sum::<f32>() / window.len() as f32;
+            let mut dot = 0.0;
+            let mut window_norm = 0.0;
+            for (value, reference_value) in window.iter().zip(&patch) {
+                let value = value - window_mean;
+                dot += value * reference_value;
+                window_norm += value * value;
+            }
+            if window_norm <= 0.0 {
+                continue;
+            }
+
+            let correlation = dot / (norm * window_norm.sqrt());
+            if correlation > best_correlation {
+                best_correlation = correlation;
+                best = Some((dx, dy));
+            }
+        }
+    }
+    best
+}
+
+/// Fits a projection to pairs of corresponding points using least squares.
+fn fit_projection(pairs: &[Match]) -> Option<Projection> {
+    // Work in units of stones to keep the equations well conditioned
+    let scale = 1.0 / STONE_SIZE as f64;
+
+    // Each pair gives two linear equations in the eight unknown entries of the matrix
+    let mut a = vec![vec![0.0; 8]; 8];
+    let mut b = vec![0.0; 8];
+    for &((x, y), (u, v)) in pairs {
+        let (x, y) = (x as f64 * scale, y as f64 * scale);
+        let (u, v) = (u as f64 * scale, v as f64 * scale);
+        for (row, target) in [
+            ([x, y, 1.0, 0.0, 0.0, 0.0, -x * u, -y * u], u),
+            ([0.0, 0.0, 0.0, x, y, 1.0, -x * v, -y * v], v),
+        ] {
+            for i in 0..8 {
+                for j in 0..8 {
+                    a[i][j] += row[i] * row[j];
+                }
+                b[i] += row[i] * target;
+            }
+        }
+    }
+    let h = solve(a, b)?;
+
+    let projection = Projection::from_matrix([
+        h[0] as f32,
+        h[1] as f32,
+        h[2] as f32,
+        h[3] as f32,
+        h[4] as f32,
+        h[5] as f32,
+        h[6] as f32,
+        h[7] as f32,
+        1.0,
+    ])?;
+    let to_stones = Projection::scale(scale as f32, scale as f32);
+    Some(to_stones.and_then(projection).and_then(to_stones.invert()))
+}
+
+/// Calculates the distance between two points.
+fn distance(a: (f32, f32), b: (f32, f32)) -> f32 {
+    ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+}
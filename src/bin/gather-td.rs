@@ -1,6 +1,8 @@
 use std::{
     fs::{self, File},
     path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
     time::{Duration, Instant},
 };
 
@@ -21,6 +23,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let (mut control_socket, _) = connect("ws://localhost:5410/ws/control").unwrap();
     let (mut camera_socket, _) = connect("ws://localhost:5410/ws/board-camera").unwrap();
+    let (mut display_socket, _) = connect("ws://localhost:5410/ws/board-display").unwrap();
     let new_training_pattern =
         Message::Text(serde_json::to_string(&ControlMessage::NewTrainingPattern).unwrap());
     _ = control_socket.send(new_training_pattern.clone());
@@ -33,6 +36,17 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .error_for_status()?
         .copy_to(&mut File::create_new(args.out.join("reference.png"))?)?;
 
+    // Keep track of what the display is projecting onto the board, to save along with each image
+    let board_display = Arc::new(Mutex::new(None));
+    let board_display_setter = board_display.clone();
+    thread::spawn(move || {
+        while let Ok(message) = display_socket.read() {
+            if let Message::Binary(data) = message {
+                *board_display_setter.lock().unwrap() = Some(deserialize_image(data));
+            }
+        }
+    });
+
     println!("Press Ctrl+C to exit.");
 
     let interval = Duration::from_secs(2);
@@ -43,6 +57,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             if Instant::now() >= next_capture {
                 let image: RgbImage = deserialize_image(data).convert();
                 image.save(args.out.join(format!("{}.png", num)))?;
+                if let Some(display) = board_display.lock().unwrap().as_ref() {
+                    let display: RgbImage = display.convert();
+                    display.save(args.out.join(format!("{}.display.png", num)))?;
+                }
                 num += 1;
 
                 next_capture += interval;
@@ -0,0 +1,107 @@
+use std::{
+    io::{BufRead, BufReader, Cursor, Write},
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use clap::Parser;
+use image::ImageFormat;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    // Load the frames, sorted numerically like the server's replay mode
+    let mut indexed_files: Vec<(PathBuf, usize)> = args
+        .frames
+        .read_dir()?
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.path();
+            if path.extension()? != "png" {
+                return None;
+            }
+            let index = path.file_stem()?.to_str()?.parse::<usize>().ok()?;
+            Some((path, index))
+        })
+        .collect();
+    indexed_files.sort_unstable_by_key(|(_, index)| *index);
+    if indexed_files.is_empty() {
+        println!("No frames found in {}.", args.frames.display());
+        return Ok(());
+    }
+
+    // Encode the frames up front, since they are sent repeatedly
+    let mut frames = vec![];
+    for (path, _) in indexed_files {
+        let mut jpeg = Cursor::new(vec![]);
+        image::open(path)?
+            .into_rgb8()
+            .write_to(&mut jpeg, ImageFormat::Jpeg)?;
+        frames.push(jpeg.into_inner());
+    }
+    let frames = Arc::new(frames);
+
+    let listener = TcpListener::bind(("0.0.0.0", args.port))?;
+    println!(
+        "Streaming {} frames on http://localhost:{}/",
+        frames.len(),
+        args.port
+    );
+    println!("Press Ctrl+C to exit.");
+
+    let interval = Duration::from_secs(1) / args.fps.max(1);
+    for stream in listener.incoming().flatten() {
+        let frames = frames.clone();
+        thread::spawn(move || {
+            // The client disconnecting ends the stream
+            let _ = serve(stream, &frames, interval);
+        });
+    }
+    Ok(())
+}
+
+/// Sends the frames to a client in a loop as a multipart MJPEG stream.
+fn serve(mut stream: TcpStream, frames: &[Vec<u8>], interval: Duration) -> std::io::Result<()> {
+    // Skip the request, since every path serves the same stream
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    stream.write_all(
+        b"HTTP/1.1 200 OK\r\n\
+        Content-Type: multipart/x-mixed-replace; boundary=frame\r\n\
+        Cache-Control: no-cache\r\n\
+        Connection: close\r\n\r\n",
+    )?;
+    for frame in frames.iter().cycle() {
+        write!(
+            stream,
+            "--frame\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+            frame.len()
+        )?;
+        stream.write_all(frame)?;
+        stream.write_all(b"\r\n")?;
+        thread::sleep(interval);
+    }
+    Ok(())
+}
+
+/// Streams a directory of recorded frames as MJPEG over HTTP, to stand in for a network camera.
+#[derive(Parser)]
+struct Args {
+    /// The directory of frames to stream, as recorded by `saigo --record`.
+    frames: PathBuf,
+
+    /// The port to listen on.
+    #[arg(long, default_value_t = 8080)]
+    port: u16,
+
+    /// How many frames to send per second.
+    #[arg(long, default_value_t = 10)]
+    fps: u32,
+}
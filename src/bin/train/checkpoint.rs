@@ -0,0 +1,177 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use saigo::model_file::{
+    ModelFormat, check_metadata, format_metadata, read_metadata, write_metadata,
+};
+use tch::{Tensor, nn::VarStore};
+
+use crate::sgd::Sgd;
+
+/// The prefixes of the names of the tensors in a checkpoint file.
+const WEIGHTS_PREFIX: &str = "weights.";
+const BEST_WEIGHTS_PREFIX: &str = "best_weights.";
+const MOMENTUM_PREFIX: &str = "momentum.";
+
+/// The keys of the metadata stored in checkpoint files, along with the format of the model.
+mod checkpoint_key {
+    pub const EPOCH: &str = "epoch";
+    pub const LR: &str = "lr";
+    pub const BEST_LOSS: &str = "best_loss";
+    pub const BEST_EPOCH: &str = "best_epoch";
+    pub const BEST_METRICS: &str = "best_metrics";
+    pub const VALIDATION_SPLIT: &str = "validation_split";
+    pub const VALIDATE_ON: &str = "validate_on";
+}
+
+/// The progress of a training run, saved at the end of each epoch and every so often during one.
+/// Together with the weights and the momentum of the optimizer, this is enough to resume the run.
+pub struct Checkpoint {
+    /// The last epoch that finished.
+    /// A checkpoint saved during an epoch has the weights from partway through the next one.
+    pub epoch: usize,
+    pub lr: f64,
+    pub best_loss: f64,
+    pub best_epoch: usize,
+    /// The metrics of the best epoch, which are stored in the model file.
+    pub best_status: String,
+    /// How the validation data was held out, which must stay the same for the best loss to mean the same thing.
+    pub validation_split: f64,
+    pub validate_on: Vec<PathBuf>,
+}
+
+impl Checkpoint {
+    /// Saves the checkpoint, along with the current weights, the weights of the best epoch, and the momentum.
+    /// The file is written next to the previous checkpoint and then moved over it,
+    /// so that a crash while saving doesn't lose the previous checkpoint.
+    pub fn save(
+        &self,
+        path: &Path,
+        format: ModelFormat,
+        vs: &VarStore,
+        best_vs: &VarStore,
+        opt: &Sgd,
+    ) -> Result<(), String> {
+        let mut tensors = Vec::new();
+        for (prefix, variables) in [
+            (WEIGHTS_PREFIX, vs.variables()),
+            (BEST_WEIGHTS_PREFIX, best_vs.variables()),
+            (MOMENTUM_PREFIX, opt.momentum()),
+        ] {
+            tensors.extend(
+                variables
+                    .into_iter()
+                    .map(|(name, tensor)| (format!("{}{}", prefix, name), tensor)),
+            );
+        }
+
+        let mut metadata = format_metadata(format);
+        metadata.extend(BTreeMap::from([
+            (checkpoint_key::EPOCH.to_string(), self.epoch.to_string()),
+            (checkpoint_key::LR.to_string(), self.lr.to_string()),
+            (
+                checkpoint_key::BEST_LOSS.to_string(),
+                self.best_loss.to_string(),
+            ),
+            (
+                checkpoint_key::BEST_EPOCH.to_string(),
+                self.best_epoch.to_string(),
+            ),
+            (
+                checkpoint_key::BEST_METRICS.to_string(),
+                self.best_status.clone(),
+            ),
+            (
+                checkpoint_key::VALIDATION_SPLIT.to_string(),
+                self.validation_split.to_string(),
+            ),
+            (
+                checkpoint_key::VALIDATE_ON.to_string(),
+                self.validate_on
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            ),
+        ]));
+
+        let temp_path = path.with_extension("tmp");
+        Tensor::write_safetensors(&tensors, &temp_path).map_err(|e| e.to_string())?;
+        write_metadata(&temp_path, &metadata).map_err(|e| e.to_string())?;
+        fs::rename(&temp_path, path).map_err(|e| e.to_string())
+    }
+
+    /// Loads a checkpoint, restoring the current weights, the weights of the best epoch, and the momentum.
+    /// The checkpoint must have been saved by a run training a model of the same format.
+    pub fn load(
+        path: &Path,
+        format: ModelFormat,
+        vs: &VarStore,
+        best_vs: &VarStore,
+        opt: &Sgd,
+    ) -> Result<Self, String> {
+        let metadata = read_metadata(path).map_err(|e| e.to_string())?;
+        if check_metadata(&metadata)? != format {
+            return Err(
+                "The checkpoint is for a model with a different architecture or input planes."
+                    .to_string(),
+            );
+        }
+        let checkpoint = Checkpoint {
+            epoch: parse(&metadata, checkpoint_key::EPOCH)?,
+            lr: parse(&metadata, checkpoint_key::LR)?,
+            best_loss: parse(&metadata, checkpoint_key::BEST_LOSS)?,
+            best_epoch: parse(&metadata, checkpoint_key::BEST_EPOCH)?,
+            best_status: parse(&metadata, checkpoint_key::BEST_METRICS)?,
+            validation_split: parse(&metadata, checkpoint_key::VALIDATION_SPLIT)?,
+            validate_on: parse::<String>(&metadata, checkpoint_key::VALIDATE_ON)?
+                .lines()
+                .map(PathBuf::from)
+                .collect(),
+        };
+
+        let tensors: HashMap<_, _> = Tensor::read_safetensors(path)
+            .map_err(|e| e.to_string())?
+            .into_iter()
+            .collect();
+        restore(&tensors, WEIGHTS_PREFIX, vs.variables())?;
+        restore(&tensors, BEST_WEIGHTS_PREFIX, best_vs.variables())?;
+        restore(&tensors, MOMENTUM_PREFIX, opt.momentum())?;
+        Ok(checkpoint)
+    }
+}
+
+/// Parses an entry of the metadata of a checkpoint.
+fn parse<T: FromStr<Err: Display>>(
+    metadata: &BTreeMap<String, String>,
+    key: &str,
+) -> Result<T, String> {
+    let value = metadata
+        .get(key)
+        .ok_or_else(|| format!("The checkpoint is missing its {}.", key))?;
+    value
+        .parse()
+        .map_err(|e| format!("The checkpoint has an invalid {}: {}", key, e))
+}
+
+/// Copies the tensors with the given prefix into the variables with the rest of their names.
+fn restore(
+    tensors: &HashMap<String, Tensor>,
+    prefix: &str,
+    variables: HashMap<String, Tensor>,
+) -> Result<(), String> {
+    tch::no_grad(|| {
+        for (name, mut variable) in variables {
+            let tensor = tensors
+                .get(&format!("{}{}", prefix, name))
+                .ok_or_else(|| format!("The checkpoint is missing {}{}.", prefix, name))?;
+            variable.f_copy_(tensor).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    })
+}
@@ -9,6 +9,7 @@ struct SampleIndex {
 }
 
 /// Shuffles and batches training data from multiple datasets.
+/// The samples in a batch must all have the same shape.
 pub struct DataLoader<'a> {
     device: Device,
     datasets: &'a Vec<Dataset>,
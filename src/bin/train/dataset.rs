@@ -1,27 +1,41 @@
 use std::{
+    collections::HashSet,
     fs::{DirEntry, read_to_string},
     path::{Path, PathBuf},
 };
 
 use image::Rgb32FImage;
+use rand::{SeedableRng, rngs::StdRng, seq::SliceRandom};
 use saigo::{
     STONE_SIZE,
-    vision_model::{LBL_BLACK, LBL_NONE, LBL_OBSCURED, LBL_WHITE, read_tensor},
+    model_file::{Architecture, ModelFormat},
+    vision_model::{
+        LBL_BLACK, LBL_NONE, LBL_OBSCURED, LBL_WHITE, crop_intersections, read_board_tensor,
+    },
 };
 use tch::{Device, Kind, Tensor};
 
+/// The seed used to pick the images held out for validation.
+const VALIDATION_SEED: u64 = 0;
+
 /// A single set of training data captured from the same board and loaded from a single directory.
+/// For [`Architecture::Crop`], each sample is a single intersection with a scalar label.
+/// For [`Architecture::Board`], each sample is a whole image with a label for each intersection.
 pub struct Dataset {
     pub path: PathBuf,
+    architecture: Architecture,
     width: u32,
     height: u32,
     image_names: Vec<String>,
     pub samples: Vec<(Tensor, Tensor)>,
+    /// The indexing tensors used to permute the color channels of the samples.
+    permutations: [Tensor; 6],
 }
 
 impl Dataset {
     /// Loads a dataset from a directory, if the directory contains a training dataset.
-    pub fn load(dir: &Path) -> Option<Self> {
+    /// Images without a display image are skipped if the model reads the display.
+    pub fn load(dir: &Path, format: ModelFormat) -> Option<Self> {
         let reference = image::open(dir.join("reference.png")).ok()?.into_rgb32f();
         println!("  {}", dir.display());
         let width = reference.width() / STONE_SIZE;
@@ -30,27 +44,45 @@ impl Dataset {
         let mut samples = Vec::new();
 
         for entry in dir.read_dir().ok()?.flatten() {
-            if let Some((name, img, labels)) = load_file(entry, width, height) {
-                for y in 0..height {
-                    for x in 0..width {
-                        let sample = read_tensor(&img, &reference, x * STONE_SIZE, y * STONE_SIZE);
-                        let label = Tensor::scalar_tensor(
-                            labels[(y * width + x) as usize] as i64,
-                            (Kind::Uint8, Device::Cpu),
-                        );
-                        samples.push((sample, label));
+            if let Some((name, img, display, labels)) =
+                load_file(entry, width, height, format.input_planes.display)
+            {
+                let display = display.unwrap_or_else(|| Rgb32FImage::new(1, 1));
+                let board = read_board_tensor(&format.input_images(&img, &reference, &display));
+                match format.architecture {
+                    Architecture::Crop => {
+                        let crops = crop_intersections(&board);
+                        for (i, &label) in labels.iter().enumerate() {
+                            let label =
+                                Tensor::scalar_tensor(label as i64, (Kind::Uint8, Device::Cpu));
+                            samples.push((crops.get(i as i64), label));
+                        }
+                    }
+                    Architecture::Board => {
+                        let label = Tensor::from_slice(&labels).view([height as i64, width as i64]);
+                        samples.push((board, label));
                     }
                 }
                 image_names.push(name);
             }
         }
 
+        let images = format.input_planes.count() as i64 / 3;
         Some(Dataset {
             path: dir.to_path_buf(),
+            architecture: format.architecture,
             width,
             height,
             image_names,
             samples,
+            permutations: [
+                permutation(0, 1, 2, images),
+                permutation(0, 2, 1, images),
+                permutation(1, 0, 2, images),
+                permutation(1, 2, 0, images),
+                permutation(2, 0, 1, images),
+                permutation(2, 1, 0, images),
+            ],
         })
     }
 
@@ -68,15 +100,74 @@ impl Dataset {
         let transformation = transformation / 6;
         let flip = transformation % 2;
         let rotation = transformation / 2;
-        let mut sample = PERMUTATIONS.with(|p| sample.index_select(0, &p[color_permutation]));
+        let mut sample = sample.index_select(0, &self.permutations[color_permutation]);
+        let mut label = label.copy();
         if flip == 1 {
             sample = sample.transpose(1, 2);
         }
         sample = sample.rot90(rotation as i64, [1, 2]);
-        (sample, label.copy())
+        // The labels of a whole board have to follow the intersections around
+        if self.architecture == Architecture::Board {
+            if flip == 1 {
+                label = label.transpose(0, 1);
+            }
+            label = label.rot90(rotation as i64, [0, 1]);
+        }
+        (sample, label)
+    }
+
+    /// Moves a random fraction of the images into a separate dataset, for validating the model on images it isn't trained on.
+    /// The intersections of an image are moved together, since they are too alike to validate each other.
+    /// The same images are picked every time, so that a resumed training run isn't validated on images it was trained on.
+    pub fn split_off(&mut self, fraction: f64) -> Dataset {
+        let images = self.image_names.len();
+        let mut order: Vec<_> = (0..images).collect();
+        order.sort_by(|&a, &b| self.image_names[a].cmp(&self.image_names[b]));
+        order.shuffle(&mut StdRng::seed_from_u64(VALIDATION_SEED));
+        let held_out: HashSet<_> = order
+            .into_iter()
+            .take((images as f64 * fraction).round() as usize)
+            .collect();
+
+        let samples_per_image = match self.architecture {
+            Architecture::Crop => (self.width * self.height) as usize,
+            Architecture::Board => 1,
+        };
+        let mut split = Dataset {
+            path: self.path.clone(),
+            architecture: self.architecture,
+            width: self.width,
+            height: self.height,
+            image_names: Vec::new(),
+            samples: Vec::new(),
+            permutations: self.permutations.each_ref().map(Tensor::shallow_clone),
+        };
+        let image_names = std::mem::take(&mut self.image_names);
+        let mut samples = std::mem::take(&mut self.samples).into_iter();
+        for (i, name) in image_names.into_iter().enumerate() {
+            let dataset = if held_out.contains(&i) {
+                &mut split
+            } else {
+                &mut *self
+            };
+            dataset.image_names.push(name);
+            dataset
+                .samples
+                .extend(samples.by_ref().take(samples_per_image));
+        }
+        split
     }
 
-    /// Returns a textual description of the location of the sample in the dataset.
+    /// Returns the number of intersections labelled in each sample.
+    pub fn intersections_per_sample(&self) -> usize {
+        match self.architecture {
+            Architecture::Crop => 1,
+            Architecture::Board => (self.width * self.height) as usize,
+        }
+    }
+
+    /// Returns a textual description of the location of an intersection in the dataset,
+    /// counting the intersections of each sample in row-major order.
     pub fn locate(&self, index: usize) -> String {
         let index = index as u32;
         let x = index % self.width;
@@ -90,24 +181,21 @@ impl Dataset {
     }
 }
 
-thread_local! {
-    static PERMUTATIONS: [Tensor; 6] = [
-        permutation(0, 1, 2),
-        permutation(0, 2, 1),
-        permutation(1, 0, 2),
-        permutation(1, 2, 0),
-        permutation(2, 0, 1),
-        permutation(2, 1, 0),
-    ];
+/// Creates an indexing tensor used to permute the color channels of a sample,
+/// applying the same permutation to each of the images in the sample.
+fn permutation(r: i64, g: i64, b: i64, images: i64) -> Tensor {
+    let indexes: Vec<i64> = (0..images)
+        .flat_map(|i| [r + i * 3, g + i * 3, b + i * 3])
+        .collect();
+    Tensor::from_slice(&indexes)
 }
 
-/// Creates an indexing tensor used to permute the color channels of a sample.
-fn permutation(r: i64, g: i64, b: i64) -> Tensor {
-    Tensor::from_slice(&[r, g, b, r + 3, g + 3, b + 3])
-}
+/// A training image, as loaded by [`load_file`].
+type TrainingImage = (String, Rgb32FImage, Option<Rgb32FImage>, Vec<u8>);
 
-/// Loads a training image from a label file, returning the name of the image, the image data, and the labels.
-fn load_file(entry: DirEntry, width: u32, height: u32) -> Option<(String, Rgb32FImage, Vec<u8>)> {
+/// Loads a training image from a label file, returning the name of the image, the image data,
+/// the display image (if requested), and the labels.
+fn load_file(entry: DirEntry, width: u32, height: u32, display: bool) -> Option<TrainingImage> {
     let path = entry.path();
 
     // If the file is not a label file, ignore it
@@ -122,8 +210,19 @@ fn load_file(entry: DirEntry, width: u32, height: u32) -> Option<(String, Rgb32F
         .map(label_from_char)
         .collect();
 
-    // Load the image
+    // Load the image, and what the display was projecting onto the board when it was captured
     let image = image::open(path.with_extension("png")).ok()?.into_rgb32f();
+    let display = if display {
+        let display = image::open(path.with_extension("display.png"))
+            .ok()?
+            .into_rgb32f();
+        if display.dimensions() != image.dimensions() {
+            return None;
+        }
+        Some(display)
+    } else {
+        None
+    };
 
     // If the image is invalid, ignore it
     if image.width() != width * STONE_SIZE
@@ -136,6 +235,7 @@ fn load_file(entry: DirEntry, width: u32, height: u32) -> Option<(String, Rgb32F
     Some((
         path.file_stem().unwrap().to_string_lossy().to_string(),
         image,
+        display,
         labels,
     ))
 }
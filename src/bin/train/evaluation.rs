@@ -0,0 +1,87 @@
+use saigo::{model_file::LABELS, vision_model::VisionNetwork};
+use tch::{Device, Kind, Tensor};
+
+use crate::dataset::Dataset;
+
+/// How well a model classifies a set of datasets, without data augmentation.
+pub struct Evaluation {
+    pub loss: f64,
+    pub accuracy: f64,
+    /// The number of intersections with each expected label (rows) that were classified as each label (columns).
+    pub confusion: [[u64; 4]; 4],
+    /// The accuracy on each dataset, in the same order as the datasets.
+    pub dataset_accuracy: Vec<f64>,
+}
+
+/// Runs the model on every sample of the datasets and compares its outputs to the labels.
+pub fn evaluate(
+    model: &dyn VisionNetwork,
+    datasets: &[Dataset],
+    batch_size: usize,
+    device: Device,
+) -> Evaluation {
+    let mut total_count = 0.0;
+    let mut total_loss = 0.0;
+    let mut confusion = [[0; 4]; 4];
+    let mut dataset_accuracy = Vec::with_capacity(datasets.len());
+    for dataset in datasets {
+        let mut count = 0.0;
+        let mut correct = 0.0;
+        for start in (0..dataset.len()).step_by(batch_size) {
+            // Indexes below the number of samples leave the samples as they are
+            let end = (start + batch_size).min(dataset.len());
+            let (samples, labels): (Vec<_>, Vec<_>) = (start..end).map(|i| dataset.get(i)).unzip();
+            let samples = Tensor::stack(&samples, 0).to(device);
+            let labels = Tensor::stack(&labels, 0).to(device).view([-1]);
+            let outputs = tch::no_grad(|| model.forward(&samples));
+            let loss = outputs.cross_entropy_for_logits(&labels);
+
+            let n = labels.size()[0] as f64;
+            total_loss += loss.double_value(&[]) * n;
+            count += n;
+            let expected: Vec<i64> = labels.to_kind(Kind::Int64).try_into().unwrap();
+            let predicted: Vec<i64> = outputs.argmax(1, false).try_into().unwrap();
+            for (expected, predicted) in expected.into_iter().zip(predicted) {
+                confusion[expected as usize][predicted as usize] += 1;
+                if expected == predicted {
+                    correct += 1.0;
+                }
+            }
+        }
+        total_count += count;
+        dataset_accuracy.push(if count > 0.0 { correct / count } else { 0.0 });
+    }
+
+    // Report zero rather than dividing by zero when there are no samples at all
+    let total_count = total_count.max(1.0);
+    let correct: u64 = (0..4).map(|i| confusion[i][i]).sum();
+    Evaluation {
+        loss: total_loss / total_count,
+        accuracy: correct as f64 / total_count,
+        confusion,
+        dataset_accuracy,
+    }
+}
+
+impl Evaluation {
+    /// Prints the confusion matrix, with a row for each expected label and a column for each output label,
+    /// followed by the accuracy on each of the datasets that were evaluated.
+    pub fn print_report(&self, datasets: &[Dataset]) {
+        let labels: Vec<_> = LABELS.split(',').collect();
+        print!("{:>10}", "");
+        for label in &labels {
+            print!("{:>10}", label);
+        }
+        println!();
+        for (label, row) in labels.iter().zip(&self.confusion) {
+            print!("{:>10}", label);
+            for count in row {
+                print!("{:>10}", count);
+            }
+            println!();
+        }
+        for (dataset, accuracy) in datasets.iter().zip(&self.dataset_accuracy) {
+            println!("  {:<.10} {}", accuracy, dataset.path.display());
+        }
+    }
+}
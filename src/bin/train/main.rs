@@ -1,31 +1,48 @@
-use clap::Parser;
+use checkpoint::Checkpoint;
+use clap::{Parser, ValueEnum};
 use dataloader::DataLoader;
 use dataset::Dataset;
-use saigo::vision_model::VisionModel;
+use evaluation::evaluate;
+use saigo::{
+    model_file::{
+        Architecture, InputPlanes, ModelFormat, format_metadata, metadata_key, write_metadata,
+    },
+    onnx::OnnxModel,
+};
+use sgd::Sgd;
 use std::{
-    fs::{self, read_dir},
+    fs::read_dir,
     path::{Path, PathBuf},
     process,
     sync::{
         Arc,
         atomic::{AtomicBool, Ordering},
     },
+    time::{Duration, Instant},
 };
-use tch::{
-    Device, Kind, Tensor,
-    nn::{self, Module, OptimizerConfig},
-};
+use tch::{Device, Kind, Tensor, nn};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
 
+mod checkpoint;
 mod dataloader;
 mod dataset;
+mod evaluation;
+mod sgd;
 
 fn main() {
     let args = Args::parse();
+    let format = ModelFormat {
+        architecture: args.architecture,
+        input_planes: InputPlanes {
+            reference: !args.reference_free,
+            display: args.display,
+        },
+    };
 
     // Load datasets into memory
     println!("Loading datasets...");
     let mut datasets = Vec::new();
-    load_datasets_recursively(&args.data, &mut datasets);
+    load_datasets_recursively(&args.data, format, &mut datasets);
 
     // Show some statistics about the distribution of training data
     if args.stats {
@@ -37,14 +54,16 @@ fn main() {
         let mut total = 0.0;
         for dataset in &datasets {
             for i in 0..dataset.len() {
-                total += 1.0;
-                let label = dataset.samples[i].1.int64_value(&[]);
-                match label {
-                    0 => none += 1.0,
-                    1 => black += 1.0,
-                    2 => white += 1.0,
-                    3 => obscured += 1.0,
-                    _ => unreachable!(),
+                let labels: Vec<i64> = dataset.samples[i].1.view([-1]).try_into().unwrap();
+                for label in labels {
+                    total += 1.0;
+                    match label {
+                        0 => none += 1.0,
+                        1 => black += 1.0,
+                        2 => white += 1.0,
+                        3 => obscured += 1.0,
+                        _ => unreachable!(),
+                    }
                 }
             }
         }
@@ -56,6 +75,36 @@ fn main() {
     }
     println!("Finished loading {} datasets", datasets.len());
 
+    // Hold out images that the model isn't trained on, to tell how well it works on images it hasn't seen
+    let validation: Vec<Dataset> = if args.validate_on.is_empty() {
+        datasets
+            .iter_mut()
+            .map(|dataset| dataset.split_off(args.validation_split))
+            .filter(|dataset| !dataset.samples.is_empty())
+            .collect()
+    } else {
+        let (validation, training): (Vec<_>, _) = datasets.into_iter().partition(|dataset| {
+            args.validate_on
+                .iter()
+                .any(|path| dataset.path.starts_with(path))
+        });
+        datasets = training;
+        // Datasets without usable images would have nothing to average the loss over
+        validation
+            .into_iter()
+            .filter(|dataset| !dataset.samples.is_empty())
+            .collect()
+    };
+    if validation.is_empty() {
+        println!("No validation data, so the training loss will be used instead");
+    } else {
+        println!(
+            "Holding out {} samples from {} datasets for validation",
+            validation.iter().map(Dataset::len).sum::<usize>(),
+            validation.len()
+        );
+    }
+
     // Handle Ctrl+C to stop training
     let exit: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
     let exit_setter = exit.clone();
@@ -70,33 +119,74 @@ fn main() {
     println!("Press Ctrl+C to exit.");
 
     // Run the training loop
-    let device = Device::Cuda(0);
+    let device = args.device.device();
+    println!("Training on {:?}", device);
     let mut vs = nn::VarStore::new(device);
-    let model = VisionModel::new(vs.root());
+    let model = format.build(vs.root());
+    // A copy of the weights from the best epoch so far, which are the ones that get saved
+    let mut best_vs = nn::VarStore::new(Device::Cpu);
+    format.build(best_vs.root());
+    // Boards can have different shapes, so whole boards are trained one at a time
+    let batch_size = match args.architecture {
+        Architecture::Crop => 1024,
+        Architecture::Board => 1,
+    };
 
     let mut best_loss = f64::INFINITY;
     let mut best_epoch = 0;
     let mut lr = 0.01;
 
-    let mut opt = nn::Sgd::default().build(&vs, lr).unwrap();
-    opt.set_momentum(0.9);
-    opt.set_weight_decay(0.001);
+    let mut opt = Sgd::new(&vs, lr, 0.9, 0.001);
 
     let mut status = String::new();
+    let mut best_status = String::new();
     let mut epoch = 0;
+
+    // Pick up where an interrupted run left off
+    let checkpoint_path = args
+        .out
+        .as_ref()
+        .map(|out| out.with_extension("checkpoint.safetensors"));
+    if args.resume {
+        let path = checkpoint_path.as_ref().unwrap();
+        let checkpoint = Checkpoint::load(path, format, &vs, &best_vs, &opt).unwrap_or_else(|e| {
+            eprintln!("Failed to load {}: {}", path.display(), e);
+            process::exit(1);
+        });
+        epoch = checkpoint.epoch;
+        lr = checkpoint.lr;
+        opt.set_lr(lr);
+        best_loss = checkpoint.best_loss;
+        best_epoch = checkpoint.best_epoch;
+        best_status = checkpoint.best_status;
+        // The best loss so far can only be compared to losses on the same validation data
+        if checkpoint.validation_split != args.validation_split
+            || checkpoint.validate_on != args.validate_on
+        {
+            eprintln!(
+                "{} was saved by a run that held out different validation data. Resume it with the same --validation-split and --validate-on.",
+                path.display()
+            );
+            process::exit(1);
+        }
+        println!("Resuming after epoch {} with LR: {}", epoch, lr);
+    }
+    let checkpoint_interval = Duration::from_secs(args.checkpoint_minutes * 60);
+    let mut last_checkpoint = Instant::now();
     while !exit.load(Ordering::Relaxed) {
         epoch += 1;
         let mut total_count = 0.0;
         let mut total_loss = 0.0;
         let mut total_acc = 0.0;
         let mut avg_loss = 0.0;
-        for batch in DataLoader::new(&datasets, 1024, device) {
+        for batch in DataLoader::new(&datasets, batch_size, device) {
             let (samples, labels) = batch;
+            let labels = labels.view([-1]);
             let outputs = model.forward(&samples);
             let loss = outputs.cross_entropy_for_logits(&labels);
             opt.backward_step(&loss);
 
-            let n = samples.size()[0] as f64;
+            let n = labels.size()[0] as f64;
             total_count += n;
             total_loss += loss.double_value(&[]) * n;
             total_acc += outputs.accuracy_for_logits(&labels).double_value(&[]) * n;
@@ -108,32 +198,121 @@ fn main() {
                 total_acc / total_count
             );
             print!("\r{}", status);
+
+            // Save the progress partway through long epochs too, resuming from the start of the epoch with the newer weights
+            if let Some(path) = &checkpoint_path
+                && args.checkpoint_minutes > 0
+                && last_checkpoint.elapsed() >= checkpoint_interval
+            {
+                let checkpoint = Checkpoint {
+                    epoch: epoch - 1,
+                    lr,
+                    best_loss,
+                    best_epoch,
+                    best_status: best_status.clone(),
+                    validation_split: args.validation_split,
+                    validate_on: args.validate_on.clone(),
+                };
+                if let Err(e) = checkpoint.save(path, format, &vs, &best_vs, &opt) {
+                    println!("\nFailed to save checkpoint: {}", e);
+                }
+                last_checkpoint = Instant::now();
+            }
         }
         println!();
 
-        if avg_loss < best_loss {
-            best_loss = avg_loss;
+        // Judge each epoch by the loss on the validation data, since the training loss keeps falling as the model overfits
+        let evaluation = (!validation.is_empty())
+            .then(|| evaluate(model.as_ref(), &validation, batch_size, device));
+        let loss = match &evaluation {
+            None => avg_loss,
+            Some(evaluation) => {
+                println!(
+                    "Validation Loss: {:<.10} Acc: {:<.10}",
+                    evaluation.loss, evaluation.accuracy
+                );
+                status = format!(
+                    "{} Val loss: {:<.10} Val acc: {:<.10}",
+                    status, evaluation.loss, evaluation.accuracy
+                );
+                evaluation.loss
+            }
+        };
+
+        if loss < best_loss {
+            best_loss = loss;
             best_epoch = epoch;
+            best_vs.copy(&vs).unwrap();
+            best_status = status.clone();
+            // Show where the new best model still goes wrong, so that a long run can be judged without waiting for it to end
+            if let Some(evaluation) = &evaluation {
+                evaluation.print_report(&validation);
+            }
         } else if epoch > best_epoch + 2 {
             lr *= 0.5;
             opt.set_lr(lr);
             println!("LR: {}", lr);
         }
+
+        // Save the progress after every epoch, so that a crash doesn't lose more than one epoch of training
+        if let Some(path) = &checkpoint_path {
+            let checkpoint = Checkpoint {
+                epoch,
+                lr,
+                best_loss,
+                best_epoch,
+                best_status: best_status.clone(),
+                validation_split: args.validation_split,
+                validate_on: args.validate_on.clone(),
+            };
+            if let Err(e) = checkpoint.save(path, format, &vs, &best_vs, &opt) {
+                println!("Failed to save checkpoint: {}", e);
+            }
+            last_checkpoint = Instant::now();
+        }
     }
 
+    // Go back to the best epoch, which may have been before the model started to overfit
+    if best_epoch != epoch {
+        println!("Keeping the model from epoch {}", best_epoch);
+        vs.copy(&best_vs).unwrap();
+    }
+    let status = best_status;
     vs.freeze();
 
+    if !validation.is_empty() {
+        println!("Validation results:");
+        evaluate(model.as_ref(), &validation, batch_size, device).print_report(&validation);
+    }
+
     // Save the model to a file
     if let Some(mut out) = args.out {
         out.set_extension("safetensors");
         vs.save(&out).unwrap();
-        let mut metadata: String = String::new();
-        for dataset in &datasets {
-            metadata += &format!("{}\n", dataset.path.display());
-        }
-        metadata += &status;
-        fs::write(out.with_extension("txt"), metadata).unwrap();
+        // Describe the model and how it was trained in the model file itself
+        let mut metadata = format_metadata(format);
+        let dataset_paths: Vec<_> = datasets
+            .iter()
+            .map(|dataset| dataset.path.display().to_string())
+            .collect();
+        metadata.insert(metadata_key::DATASETS.to_string(), dataset_paths.join("\n"));
+        metadata.insert(metadata_key::METRICS.to_string(), status.clone());
+        metadata.insert(
+            metadata_key::CREATED.to_string(),
+            OffsetDateTime::now_utc().format(&Rfc3339).unwrap(),
+        );
+        write_metadata(&out, &metadata).unwrap();
         println!("Model saved to {}", out.display());
+
+        // Export the model for builds of Saigo without LibTorch
+        if args.onnx {
+            let mut graph = OnnxModel::new("input");
+            model.export_onnx(&mut graph, "input");
+            graph.metadata = metadata;
+            let onnx_out = out.with_extension("onnx");
+            graph.save(&onnx_out).unwrap();
+            println!("Model exported to {}", onnx_out.display());
+        }
     }
 
     // Show the samples that were hardest to learn
@@ -141,27 +320,37 @@ fn main() {
         println!("Inspecting training data...");
         let mut top10: Vec<(f64, i64, Tensor, String)> = Vec::new();
         for dataset in &datasets {
+            let intersections = dataset.intersections_per_sample();
             for i in 0..dataset.len() {
-                let (sample, label) = &dataset.samples[i];
-                let output = model.forward(&sample.to(device)).softmax(1, Kind::Float);
-                let expected = label.int64_value(&[]);
-                let label_acc = output.double_value(&[0, expected]);
-                let index = top10
-                    .binary_search_by(|(a, _, _, _)| {
-                        a.partial_cmp(&label_acc)
-                            .unwrap_or(std::cmp::Ordering::Greater)
-                    })
-                    .unwrap_or_else(|i| i);
-                top10.insert(
-                    index,
-                    (
-                        label_acc,
-                        expected,
-                        output,
-                        format!("Dataset: {} {}", dataset.path.display(), dataset.locate(i)),
-                    ),
-                );
-                top10.truncate(10);
+                let (sample, labels) = &dataset.samples[i];
+                let outputs = model
+                    .forward(&sample.unsqueeze(0).to(device))
+                    .softmax(1, Kind::Float);
+                let labels: Vec<i64> = labels.view([-1]).try_into().unwrap();
+                for (j, expected) in labels.into_iter().enumerate() {
+                    let output = outputs.get(j as i64).unsqueeze(0);
+                    let label_acc = output.double_value(&[0, expected]);
+                    let index = top10
+                        .binary_search_by(|(a, _, _, _)| {
+                            a.partial_cmp(&label_acc)
+                                .unwrap_or(std::cmp::Ordering::Greater)
+                        })
+                        .unwrap_or_else(|i| i);
+                    top10.insert(
+                        index,
+                        (
+                            label_acc,
+                            expected,
+                            output,
+                            format!(
+                                "Dataset: {} {}",
+                                dataset.path.display(),
+                                dataset.locate(i * intersections + j)
+                            ),
+                        ),
+                    );
+                    top10.truncate(10);
+                }
             }
         }
 
@@ -174,15 +363,15 @@ fn main() {
 }
 
 /// Loads all datasets in the specified directory and its subdirectories.
-fn load_datasets_recursively(dir: &Path, datasets: &mut Vec<Dataset>) {
-    if let Some(dataset) = Dataset::load(dir) {
+fn load_datasets_recursively(dir: &Path, format: ModelFormat, datasets: &mut Vec<Dataset>) {
+    if let Some(dataset) = Dataset::load(dir, format) {
         datasets.push(dataset);
     }
 
     for entry in read_dir(dir).unwrap().flatten() {
         let path = entry.path();
         if path.is_dir() {
-            load_datasets_recursively(&path, datasets);
+            load_datasets_recursively(&path, format, datasets);
         }
     }
 }
@@ -197,6 +386,50 @@ struct Args {
     #[arg(short, long)]
     out: Option<PathBuf>,
 
+    /// Also export the model to ONNX format next to the model file,
+    /// for running it with builds of Saigo that don't include LibTorch.
+    #[arg(long)]
+    onnx: bool,
+
+    /// The network architecture to train: "crop" classifies each intersection on its own,
+    /// and "board" classifies the whole board at once, taking neighbouring intersections into account.
+    #[arg(short, long, default_value = "crop")]
+    architecture: Architecture,
+
+    /// Also give the model what the display was projecting onto the board, so that it learns to ignore projected light.
+    /// Only images captured along with a display image are used.
+    #[arg(short, long)]
+    display: bool,
+
+    /// Train a model that reads the board image alone, without comparing it to the reference image.
+    /// The server falls back to such a model until a reference image is taken.
+    #[arg(short, long)]
+    reference_free: bool,
+
+    /// The fraction of the images in each dataset to hold out for validation.
+    /// The learning rate and the saved model are chosen by the loss on these images.
+    #[arg(long, default_value_t = 0.1)]
+    validation_split: f64,
+
+    /// Hold out whole datasets for validation instead of part of each dataset, to check that the model works on boards it hasn't seen.
+    /// Datasets in the given directory or its subdirectories are held out. Can be given more than once.
+    #[arg(long)]
+    validate_on: Vec<PathBuf>,
+
+    /// The device to train on: "auto" uses the first CUDA device if there is one, and the CPU otherwise.
+    #[arg(long, default_value = "auto")]
+    device: TrainingDevice,
+
+    /// Continue an interrupted training run from the checkpoint saved next to the model file.
+    /// A checkpoint is saved after every epoch while training with --out.
+    #[arg(long, requires = "out")]
+    resume: bool,
+
+    /// Also save a checkpoint after this many minutes of an epoch, so that a crash doesn't lose a whole long epoch.
+    /// Resuming from such a checkpoint starts the epoch over with the weights from partway through it. 0 turns this off.
+    #[arg(long, default_value_t = 10)]
+    checkpoint_minutes: u64,
+
     /// Print training data statistics before training.
     #[arg(short, long)]
     stats: bool,
@@ -205,3 +438,22 @@ struct Args {
     #[arg(short, long)]
     inspect: bool,
 }
+
+/// The devices that a model can be trained on.
+#[derive(Clone, Copy, ValueEnum)]
+enum TrainingDevice {
+    Auto,
+    Cpu,
+    Cuda,
+}
+
+impl TrainingDevice {
+    /// Returns the device to train on.
+    fn device(self) -> Device {
+        match self {
+            TrainingDevice::Auto => Device::cuda_if_available(),
+            TrainingDevice::Cpu => Device::Cpu,
+            TrainingDevice::Cuda => Device::Cuda(0),
+        }
+    }
+}
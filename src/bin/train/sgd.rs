@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+
+use tch::{Tensor, nn::VarStore};
+
+/// Stochastic gradient descent with momentum and weight decay, which updates the weights the same way as LibTorch's SGD.
+/// LibTorch's optimizers don't expose their momentum, so it's kept here where it can be saved to a checkpoint.
+pub struct Sgd {
+    /// The name of each trainable variable, the variable, and its momentum.
+    variables: Vec<(String, Tensor, Tensor)>,
+    lr: f64,
+    momentum: f64,
+    weight_decay: f64,
+}
+
+impl Sgd {
+    /// Creates an optimizer for the trainable variables of a var store, starting without momentum.
+    pub fn new(vs: &VarStore, lr: f64, momentum: f64, weight_decay: f64) -> Self {
+        let mut variables: Vec<_> = vs
+            .variables()
+            .into_iter()
+            .filter(|(_, variable)| variable.requires_grad())
+            .map(|(name, variable)| {
+                let buffer = variable.zeros_like();
+                (name, variable, buffer)
+            })
+            .collect();
+        variables.sort_by(|(a, _, _), (b, _, _)| a.cmp(b));
+        Self {
+            variables,
+            lr,
+            momentum,
+            weight_decay,
+        }
+    }
+
+    /// Sets the learning rate.
+    pub fn set_lr(&mut self, lr: f64) {
+        self.lr = lr;
+    }
+
+    /// Returns the momentum of each variable by the name of the variable.
+    /// The tensors share their data with the optimizer, so they can also be used to restore the momentum.
+    pub fn momentum(&self) -> HashMap<String, Tensor> {
+        self.variables
+            .iter()
+            .map(|(name, _, buffer)| (name.clone(), buffer.shallow_clone()))
+            .collect()
+    }
+
+    /// Computes the gradients of the loss and takes a step down them.
+    pub fn backward_step(&mut self, loss: &Tensor) {
+        for (_, variable, _) in &mut self.variables {
+            variable.zero_grad();
+        }
+        loss.backward();
+        tch::no_grad(|| {
+            for (_, variable, buffer) in &mut self.variables {
+                let grad = variable.grad();
+                if !grad.defined() {
+                    continue;
+                }
+                let grad = grad + &*variable * self.weight_decay;
+                let _ = buffer.g_mul_scalar_(self.momentum).g_add_(&grad);
+                let _ = variable.g_sub_(&(&*buffer * self.lr));
+            }
+        });
+    }
+}
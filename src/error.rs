@@ -19,6 +19,10 @@ pub enum SaigoError {
     InvalidProfileName(String),
     NonexistentProfile(String),
     Locked(String),
+    InvalidCalibration(String),
+    InvalidCameraConfig(String),
+    CameraUnavailable(String),
+    InvalidModel(String),
     Confy(ConfyError),
     Image(ImageError),
     IO(io::Error),
@@ -35,6 +39,10 @@ impl Display for SaigoError {
                 write!(f, "Profile '{}' does not exist.", profile)
             }
             SaigoError::Locked(message) => write!(f, "{}", message),
+            SaigoError::InvalidCalibration(message) => write!(f, "{}", message),
+            SaigoError::InvalidCameraConfig(message) => write!(f, "{}", message),
+            SaigoError::CameraUnavailable(message) => write!(f, "{}", message),
+            SaigoError::InvalidModel(message) => write!(f, "Invalid model {}", message),
             SaigoError::Confy(error) => write!(f, "Confy error: {}", error),
             SaigoError::Image(error) => write!(f, "Image error: {}", error),
             SaigoError::IO(error) => write!(f, "IO error: {}", error),
@@ -51,6 +59,10 @@ impl IntoResponse for SaigoError {
             SaigoError::InvalidProfileName(_) => StatusCode::BAD_REQUEST,
             SaigoError::NonexistentProfile(_) => StatusCode::BAD_REQUEST,
             SaigoError::Locked(_) => StatusCode::CONFLICT,
+            SaigoError::InvalidCalibration(_) => StatusCode::BAD_REQUEST,
+            SaigoError::InvalidCameraConfig(_) => StatusCode::BAD_REQUEST,
+            SaigoError::CameraUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            SaigoError::InvalidModel(_) => StatusCode::BAD_REQUEST,
             SaigoError::Confy(_) => StatusCode::INTERNAL_SERVER_ERROR,
             SaigoError::Image(_) => StatusCode::INTERNAL_SERVER_ERROR,
             SaigoError::IO(_) => StatusCode::INTERNAL_SERVER_ERROR,
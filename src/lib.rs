@@ -4,6 +4,11 @@ use goban::pieces::{stones::Color, util::coord::Coord};
 use image::RgbaImage;
 use serde::{Deserialize, Serialize};
 
+pub mod model_file;
+#[cfg(feature = "onnx")]
+pub mod onnx;
+pub mod recognizer;
+#[cfg(feature = "tch")]
 pub mod vision_model;
 
 /// The width of a stone in pixels on the normalized image of the board.
@@ -1,8 +1,14 @@
-use std::{future::Future, io::Cursor, sync::Arc};
+use std::{future::Future, io::Cursor, path::PathBuf, sync::Arc};
 
 use app::{
-    AppState, DisplayState,
-    config::{self, BoardConfig, CameraConfig, Config, DisplayConfig},
+    AppState, DisplayState, RecognitionPreview,
+    camera::{CameraStatus, is_custom_device},
+    config::{
+        self, BoardConfig, CameraConfig, Config, DisplayConfig, LensDistortion, ModelConfig, Point,
+        RecognitionConfig,
+    },
+    detect::BoardDetection,
+    model::ModelStatus,
 };
 use axum::{
     Json, Router,
@@ -10,10 +16,11 @@ use axum::{
         Query, State, WebSocketUpgrade,
         ws::{Message, WebSocket},
     },
-    http::header,
+    http::{HeaderMap, HeaderValue, header},
     response::{IntoResponse, Result},
     routing::{MethodRouter, get, post},
 };
+use clap::Parser;
 use error::SaigoError;
 use goban::pieces::{goban::Goban, stones::Color};
 use image::{ImageFormat, RgbImage, RgbaImage, buffer::ConvertBuffer};
@@ -33,19 +40,37 @@ mod app;
 mod error;
 mod sync;
 
+/// The response header that carries the quality report of the reference image.
+const REFERENCE_QUALITY_HEADER: &str = "x-reference-quality";
+
 #[tokio::main]
 async fn main() {
+    let args = Args::parse();
+    if let Some(record) = &args.record {
+        if record.try_exists().unwrap_or(true) {
+            println!(
+                "Directory {} already exists and will not be overwritten.",
+                record.display()
+            );
+            return;
+        }
+        std::fs::create_dir_all(record).expect("Failed to create recording directory");
+    }
+
     nokhwa::nokhwa_initialize(|_| {});
-    let state = AppState::start();
+    let state = AppState::start(args.replay, args.record);
     let app = Router::new()
         .nest_service("/", ServeDir::new("html/saigo"))
         .route("/ws/display", websocket(websocket_display))
         .route("/ws/control", get(get_websocket_control))
         .route("/ws/camera", websocket(websocket_camera))
         .route("/ws/board-camera", websocket(websocket_board_camera))
+        .route("/ws/board-display", websocket(websocket_board_display))
+        .route("/ws/camera-status", websocket(websocket_camera_status))
         .route("/ws/raw-board", websocket(websocket_raw_board))
         .route("/ws/board", websocket(websocket_board))
         .route("/ws/game", websocket(websocket_game))
+        .route("/ws/hands", websocket(websocket_hands))
         .route("/api/config/profiles", get(get_config_profiles))
         .route("/api/config/save", post(post_config_save))
         .route("/api/config/load", post(post_config_load))
@@ -62,11 +87,38 @@ async fn main() {
             "/api/config/camera",
             get(get_config_camera).put(put_config_camera),
         )
+        .route(
+            "/api/config/recognition",
+            get(get_config_recognition).put(put_config_recognition),
+        )
+        .route(
+            "/api/config/recognition/preview",
+            post(post_config_recognition_preview),
+        )
+        .route("/api/model", get(get_model).put(put_model))
         .route("/api/cameras", get(get_cameras))
+        .route("/api/camera/status", get(get_camera_status))
+        .route("/api/camera/realign", post(post_camera_realign))
         .route(
             "/api/config/camera/reference",
             post(post_camera_config_reference),
         )
+        .route(
+            "/api/config/camera/reference/reset",
+            post(post_config_camera_reference_reset),
+        )
+        .route(
+            "/api/config/camera/distortion",
+            get(get_config_camera_distortion).put(put_config_camera_distortion),
+        )
+        .route(
+            "/api/config/camera/distortion/estimate",
+            post(post_config_camera_distortion_estimate),
+        )
+        .route(
+            "/api/config/camera/auto-detect",
+            post(post_config_camera_auto_detect),
+        )
         .with_state(state);
 
     let listener = TcpListener::bind("0.0.0.0:5410").await.unwrap();
@@ -78,6 +130,18 @@ async fn main() {
     axum::serve(listener, app).await.unwrap();
 }
 
+/// Runs the Saigo server.
+#[derive(Parser)]
+struct Args {
+    /// A directory of recorded frames to replay instead of reading from a camera.
+    #[arg(long)]
+    replay: Option<PathBuf>,
+
+    /// A directory to record the raw camera frames to, for later replay.
+    #[arg(long)]
+    record: Option<PathBuf>,
+}
+
 /// Watches for display updates and sends them to the client.
 async fn websocket_display(state: Arc<RwLock<AppState>>, socket: WebSocket) {
     let stream =
@@ -201,6 +265,23 @@ async fn websocket_board_camera(state: Arc<RwLock<AppState>>, socket: WebSocket)
     stream_to_socket(stream, socket).await;
 }
 
+/// Watches for the display as it is projected onto the board and sends it to the client.
+async fn websocket_board_display(state: Arc<RwLock<AppState>>, socket: WebSocket) {
+    let _board_config_lock;
+    let stream;
+    {
+        let state = state.read().await;
+
+        // Lock the board configuration
+        _board_config_lock = state.lock_board_config().await;
+
+        stream = WatchStream::new(state.subscribe_to_board_display_broadcast())
+            .map(|image| serialize_image(image.convert()));
+    }
+
+    stream_to_socket(stream, socket).await;
+}
+
 /// Watches for raw board frames and sends them to the client.
 async fn websocket_raw_board(state: Arc<RwLock<AppState>>, socket: WebSocket) {
     let _board_config_lock;
@@ -218,6 +299,14 @@ async fn websocket_raw_board(state: Arc<RwLock<AppState>>, socket: WebSocket) {
     stream_to_socket(stream, socket).await;
 }
 
+/// Watches for camera status updates and sends them to the client.
+async fn websocket_camera_status(state: Arc<RwLock<AppState>>, socket: WebSocket) {
+    let stream = WatchStream::new(state.read().await.subscribe_to_camera_status_broadcast())
+        .map(|status| Message::Text(serde_json::to_string(&status).unwrap()));
+
+    stream_to_socket(stream, socket).await;
+}
+
 /// Watches for board updates and sends them to the client.
 async fn websocket_board(state: Arc<RwLock<AppState>>, socket: WebSocket) {
     let _board_config_lock;
@@ -252,6 +341,24 @@ async fn websocket_game(state: Arc<RwLock<AppState>>, socket: WebSocket) {
     stream_to_socket(stream, socket).await;
 }
 
+/// Watches for hands entering and leaving the board and sends them to the client.
+async fn websocket_hands(state: Arc<RwLock<AppState>>, socket: WebSocket) {
+    let _board_config_lock;
+    let stream;
+    {
+        let state = state.read().await;
+
+        // Lock the board configuration
+        _board_config_lock = state.lock_board_config().await;
+
+        // Skip events that were missed because the client fell behind
+        stream = BroadcastStream::new(state.subscribe_to_hand_broadcast())
+            .filter_map(|event| Some(Message::Text(serde_json::to_string(&event.ok()?).unwrap())));
+    }
+
+    stream_to_socket(stream, socket).await;
+}
+
 /// Sends updates to the client.
 async fn stream_to_socket(
     mut stream: impl Stream<Item = Message> + Unpin + Send,
@@ -394,13 +501,78 @@ async fn put_config_camera(
     Ok(())
 }
 
+/// Gets the current recognition configuration.
+async fn get_config_recognition(
+    State(state): State<Arc<RwLock<AppState>>>,
+) -> Json<RecognitionConfig> {
+    Json(state.read().await.get_recognition_config().clone())
+}
+
+/// Updates the recognition configuration.
+async fn put_config_recognition(
+    State(state): State<Arc<RwLock<AppState>>>,
+    Json(recognition): Json<RecognitionConfig>,
+) -> Result<()> {
+    state.write().await.set_recognition_config(recognition)?;
+    Ok(())
+}
+
+/// Shows how the current board would be read with the given recognition configuration.
+async fn post_config_recognition_preview(
+    State(state): State<Arc<RwLock<AppState>>>,
+    Json(recognition): Json<RecognitionConfig>,
+) -> Json<RecognitionPreview> {
+    Json(state.read().await.preview_recognition(&recognition))
+}
+
+/// Gets the configured vision model and whether it could be loaded.
+async fn get_model(State(state): State<Arc<RwLock<AppState>>>) -> Json<ModelStatus> {
+    Json(state.read().await.get_model_status())
+}
+
+/// Loads a new vision model and switches to it.
+async fn put_model(
+    State(state): State<Arc<RwLock<AppState>>>,
+    Json(model): Json<ModelConfig>,
+) -> Result<()> {
+    AppState::set_model(&state, model).await?;
+    Ok(())
+}
+
 /// Gets a list of available cameras.
-async fn get_cameras() -> Result<Json<Vec<String>>> {
-    let cameras = nokhwa::query(ApiBackend::Auto).map_err(SaigoError::Nokhwa)?;
-    let names = cameras.iter().map(|camera| camera.human_name()).collect();
+async fn get_cameras(State(state): State<Arc<RwLock<AppState>>>) -> Result<Json<Vec<String>>> {
+    // Machines without a camera backend can still use a replay directory or network stream
+    let mut names: Vec<String> = match nokhwa::query(ApiBackend::Auto) {
+        Ok(cameras) => cameras.iter().map(|camera| camera.human_name()).collect(),
+        Err(e) => {
+            println!("Failed to list cameras: {}", e);
+            vec![]
+        }
+    };
+
+    // Include the configured replay directory or network stream, since they can't be discovered
+    let device = state.read().await.get_camera_config().device.clone();
+    if is_custom_device(&device) {
+        names.push(device);
+    }
+
     Ok(Json(names))
 }
 
+/// Gets the current status of the camera.
+async fn get_camera_status(State(state): State<Arc<RwLock<AppState>>>) -> Json<CameraStatus> {
+    Json(state.read().await.get_camera_status())
+}
+
+/// Corrects the corner points to follow the board after it has moved.
+async fn post_camera_realign(
+    State(state): State<Arc<RwLock<AppState>>>,
+) -> Result<Json<CameraConfig>> {
+    let mut state = state.write().await;
+    tokio::task::block_in_place(|| state.realign_camera())?;
+    Ok(Json(state.get_camera_config().clone()))
+}
+
 /// Used for deserializing the query string for [`post_camera_config_reference`].
 #[derive(Deserialize)]
 struct Take {
@@ -413,22 +585,75 @@ async fn post_camera_config_reference(
     Query(Take { take }): Query<Take>,
 ) -> Result<impl IntoResponse> {
     if take {
-        state.write().await.take_reference_image()?;
+        AppState::take_reference_image(&state).await?;
     }
 
-    // Encode the result as a PNG image
-    let image = state
-        .read()
-        .await
-        .get_camera_config()
-        .reference_image
-        .clone()
-        .unwrap_or(RgbImage::new(1, 1));
+    let image;
+    let quality;
+    {
+        let state = state.read().await;
+        image = state
+            .get_camera_config()
+            .reference_image
+            .clone()
+            .unwrap_or(RgbImage::new(1, 1));
+        quality = state.get_reference_quality().cloned();
+    }
+
+    // Encode the result as a PNG image, with the quality report in a header
     let mut writer = Cursor::new(vec![]);
     image
         .write_to(&mut writer, ImageFormat::Png)
         .map_err(SaigoError::Image)?;
-    Ok(([(header::CONTENT_TYPE, "image/png")], writer.into_inner()))
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("image/png"));
+    if let Some(quality) = quality {
+        let json = serde_json::to_string(&quality).unwrap();
+        headers.insert(
+            REFERENCE_QUALITY_HEADER,
+            HeaderValue::from_str(&json).unwrap(),
+        );
+    }
+    Ok((headers, writer.into_inner()))
+}
+
+/// Discards the changes made to the reference image to follow the lighting.
+async fn post_config_camera_reference_reset(State(state): State<Arc<RwLock<AppState>>>) {
+    state.write().await.reset_adapted_reference();
+}
+
+/// Gets the current lens distortion coefficients.
+async fn get_config_camera_distortion(
+    State(state): State<Arc<RwLock<AppState>>>,
+) -> Json<LensDistortion> {
+    Json(state.read().await.get_camera_config().distortion.clone())
+}
+
+/// Updates the lens distortion coefficients.
+async fn put_config_camera_distortion(
+    State(state): State<Arc<RwLock<AppState>>>,
+    Json(distortion): Json<LensDistortion>,
+) -> Result<()> {
+    state.write().await.set_lens_distortion(distortion)?;
+    Ok(())
+}
+
+/// Estimates the lens distortion coefficients from points along the edges of the grid, without applying them.
+async fn post_config_camera_distortion_estimate(
+    State(state): State<Arc<RwLock<AppState>>>,
+    Json(points): Json<Vec<Point>>,
+) -> Result<Json<LensDistortion>> {
+    let distortion = state.read().await.estimate_lens_distortion(&points)?;
+    Ok(Json(distortion))
+}
+
+/// Detects the board's grid in the current camera frame and proposes a camera configuration, without applying it.
+async fn post_config_camera_auto_detect(
+    State(state): State<Arc<RwLock<AppState>>>,
+) -> Result<Json<BoardDetection>> {
+    let state = state.read().await;
+    let detection = tokio::task::block_in_place(|| state.detect_board())?;
+    Ok(Json(detection))
 }
 
 /// Helper function for creating a WebSocket route.
@@ -0,0 +1,262 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Formatter},
+    fs::{self, File},
+    io::{self, Read},
+    path::Path,
+    str::FromStr,
+};
+
+use image::Rgb32FImage;
+use serde_json::{Map, Value};
+
+use crate::STONE_SIZE;
+
+/// The network architectures that a model file can contain.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Architecture {
+    /// Classifies each intersection separately from a crop around it.
+    Crop,
+    /// Classifies all intersections at once from the whole board.
+    Board,
+}
+
+impl Display for Architecture {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Architecture::Crop => write!(f, "crop"),
+            Architecture::Board => write!(f, "board"),
+        }
+    }
+}
+
+impl FromStr for Architecture {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "crop" => Ok(Architecture::Crop),
+            "board" => Ok(Architecture::Board),
+            _ => Err(format!("Unknown architecture '{}'.", s)),
+        }
+    }
+}
+
+/// The names of the labels, in the order of the model's outputs.
+pub const LABELS: &str = "none,black,white,obscured";
+
+/// The names of the input planes read from the board image.
+const IMAGE_PLANES: &str = "image_r,image_g,image_b";
+
+/// The names of the input planes read from the reference image.
+const REFERENCE_PLANES: &str = "reference_r,reference_g,reference_b";
+
+/// The names of the input planes read from the display as it is projected onto the board.
+const DISPLAY_PLANES: &str = "display_r,display_g,display_b";
+
+/// The images that a model reads, each providing three input planes for R, G, B.
+/// The board image is always read first.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct InputPlanes {
+    /// Whether the model compares the board to a reference image of the empty board.
+    /// Models that don't can run before a reference image is taken, and aren't affected by changes in lighting since then.
+    pub reference: bool,
+    /// Whether the model reads what the display is projecting onto the board,
+    /// so that it can tell projected light apart from stones and hands.
+    pub display: bool,
+}
+
+impl InputPlanes {
+    /// Returns the number of input planes.
+    pub fn count(self) -> usize {
+        3 * (1 + self.reference as usize + self.display as usize)
+    }
+}
+
+impl Display for InputPlanes {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", IMAGE_PLANES)?;
+        if self.reference {
+            write!(f, ",{}", REFERENCE_PLANES)?;
+        }
+        if self.display {
+            write!(f, ",{}", DISPLAY_PLANES)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for InputPlanes {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [(true, false), (true, true), (false, false), (false, true)]
+            .into_iter()
+            .map(|(reference, display)| InputPlanes { reference, display })
+            .find(|input_planes| input_planes.to_string() == s)
+            .ok_or_else(|| {
+                format!(
+                    "The model reads the input planes {}, which this version of Saigo doesn't provide.",
+                    s
+                )
+            })
+    }
+}
+
+/// The network architecture and inputs of a model, which are needed to run it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ModelFormat {
+    pub architecture: Architecture,
+    pub input_planes: InputPlanes,
+}
+
+impl ModelFormat {
+    /// Selects the images that the model reads, in the order of its input planes.
+    pub fn input_images<'a>(
+        self,
+        image: &'a Rgb32FImage,
+        reference: &'a Rgb32FImage,
+        display: &'a Rgb32FImage,
+    ) -> Vec<&'a Rgb32FImage> {
+        let mut images = vec![image];
+        if self.input_planes.reference {
+            images.push(reference);
+        }
+        if self.input_planes.display {
+            images.push(display);
+        }
+        images
+    }
+}
+
+/// The keys of the metadata stored in model files.
+pub mod metadata_key {
+    /// The network architecture, as parsed by [`Architecture`](super::Architecture).
+    pub const ARCHITECTURE: &str = "architecture";
+    /// The size of a stone in the board image, in pixels.
+    pub const STONE_SIZE: &str = "stone_size";
+    /// The names of the labels, in the order of the model's outputs.
+    pub const LABELS: &str = "labels";
+    /// The names of the input planes, in the order the model reads them, as formatted by [`InputPlanes`](super::InputPlanes).
+    pub const INPUT_PLANES: &str = "input_planes";
+    /// The paths of the datasets the model was trained on, one per line.
+    pub const DATASETS: &str = "datasets";
+    /// The training metrics at the end of the last epoch.
+    pub const METRICS: &str = "metrics";
+    /// When the model was saved, in RFC 3339 format.
+    pub const CREATED: &str = "created";
+}
+
+/// Describes the inputs and outputs of a model with the given format, for storing in the model file.
+pub fn format_metadata(format: ModelFormat) -> BTreeMap<String, String> {
+    BTreeMap::from([
+        (
+            metadata_key::ARCHITECTURE.to_string(),
+            format.architecture.to_string(),
+        ),
+        (metadata_key::STONE_SIZE.to_string(), STONE_SIZE.to_string()),
+        (metadata_key::LABELS.to_string(), LABELS.to_string()),
+        (
+            metadata_key::INPUT_PLANES.to_string(),
+            format.input_planes.to_string(),
+        ),
+    ])
+}
+
+/// Checks that the metadata of a model file matches the inputs and outputs of this build, returning the model format.
+/// Model files from before the metadata was stored don't have it, so missing entries are assumed to match.
+pub fn check_metadata(metadata: &BTreeMap<String, String>) -> Result<ModelFormat, String> {
+    if let Some(stone_size) = metadata.get(metadata_key::STONE_SIZE)
+        && *stone_size != STONE_SIZE.to_string()
+    {
+        return Err(format!(
+            "The model was trained on stones of {} pixels, but this version of Saigo uses {} pixels.",
+            stone_size, STONE_SIZE
+        ));
+    }
+    if let Some(labels) = metadata.get(metadata_key::LABELS)
+        && labels != LABELS
+    {
+        return Err(format!(
+            "The model outputs the labels {}, but this version of Saigo expects {}.",
+            labels, LABELS
+        ));
+    }
+    let input_planes = match metadata.get(metadata_key::INPUT_PLANES) {
+        Some(input_planes) => input_planes.parse()?,
+        None => InputPlanes {
+            reference: true,
+            display: false,
+        },
+    };
+    let architecture = match metadata.get(metadata_key::ARCHITECTURE) {
+        Some(architecture) => architecture.parse()?,
+        None => Architecture::Crop,
+    };
+    Ok(ModelFormat {
+        architecture,
+        input_planes,
+    })
+}
+
+/// The largest safetensors header to read, to avoid allocating huge buffers for files that aren't models.
+const MAX_HEADER_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Reads the header of a safetensors file, which describes the tensors and contains the metadata.
+fn read_header(file: &mut impl Read) -> io::Result<Map<String, Value>> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "The file is not a safetensors model.",
+        )
+    };
+
+    // The header is a JSON object preceded by its length
+    let mut length = [0; 8];
+    file.read_exact(&mut length)?;
+    let length = u64::from_le_bytes(length);
+    if length > MAX_HEADER_SIZE {
+        return Err(invalid());
+    }
+    let mut header = vec![0; length as usize];
+    file.read_exact(&mut header)?;
+    match serde_json::from_slice(&header) {
+        Ok(Value::Object(header)) => Ok(header),
+        _ => Err(invalid()),
+    }
+}
+
+/// Reads the metadata from the header of a safetensors file.
+pub fn read_metadata(path: &Path) -> io::Result<BTreeMap<String, String>> {
+    let header = read_header(&mut File::open(path)?)?;
+    Ok(header
+        .get("__metadata__")
+        .and_then(|metadata| metadata.as_object())
+        .map(|metadata| {
+            metadata
+                .iter()
+                .filter_map(|(key, value)| Some((key.clone(), value.as_str()?.to_string())))
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+/// Replaces the metadata in the header of a safetensors file.
+pub fn write_metadata(path: &Path, metadata: &BTreeMap<String, String>) -> io::Result<()> {
+    let contents = fs::read(path)?;
+    let mut reader = contents.as_slice();
+    let mut header = read_header(&mut reader)?;
+    header.insert(
+        "__metadata__".to_string(),
+        serde_json::to_value(metadata).unwrap(),
+    );
+
+    // Pad the header with spaces to keep the tensor data aligned
+    let mut header = serde_json::to_vec(&header).unwrap();
+    header.resize(header.len().next_multiple_of(8), b' ');
+    let mut file = Vec::with_capacity(8 + header.len() + reader.len());
+    file.extend_from_slice(&(header.len() as u64).to_le_bytes());
+    file.extend_from_slice(&header);
+    file.extend_from_slice(reader);
+    fs::write(path, file)
+}
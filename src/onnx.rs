@@ -0,0 +1,1231 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::Path,
+};
+
+use image::Rgb32FImage;
+
+use crate::{
+    STONE_SIZE,
+    model_file::{Architecture, ModelFormat, check_metadata},
+    recognizer::{BoardRecognizer, VisionModelOutput, pick_intersections},
+};
+
+/// The version of the ONNX file format that is written.
+const IR_VERSION: i64 = 8;
+
+/// The version of the ONNX operator set that is written.
+const OPSET_VERSION: i64 = 13;
+
+/// The ONNX data types that are used.
+const FLOAT: i64 = 1;
+const INT64: i64 = 7;
+
+/// The ONNX attribute types that are used.
+const ATTRIBUTE_INT: i64 = 2;
+const ATTRIBUTE_INTS: i64 = 7;
+
+/// A dense multidimensional array of floats in row-major order.
+#[derive(Clone, Debug)]
+pub struct OnnxTensor {
+    pub shape: Vec<usize>,
+    pub data: Vec<f32>,
+}
+
+impl OnnxTensor {
+    /// Creates a tensor, checking that the data fits the shape.
+    pub fn new(shape: Vec<usize>, data: Vec<f32>) -> Result<Self, String> {
+        // A broken file can have a shape too large to count
+        let size = shape
+            .iter()
+            .try_fold(1usize, |size, &dim| size.checked_mul(dim));
+        if size != Some(data.len()) {
+            return Err(format!(
+                "A tensor of shape {:?} can't hold {} values.",
+                shape,
+                data.len()
+            ));
+        }
+        Ok(Self { shape, data })
+    }
+
+    /// Gets the shape of a tensor that must have four dimensions.
+    fn shape4(&self) -> Result<[usize; 4], String> {
+        self.shape
+            .as_slice()
+            .try_into()
+            .map_err(|_| format!("Expected 4 dimensions, but got {:?}.", self.shape))
+    }
+
+    /// Gets the shape of a tensor that must have two dimensions.
+    fn shape2(&self) -> Result<[usize; 2], String> {
+        self.shape
+            .as_slice()
+            .try_into()
+            .map_err(|_| format!("Expected 2 dimensions, but got {:?}.", self.shape))
+    }
+}
+
+/// An attribute of an operator.
+#[derive(Clone, Debug)]
+pub enum Attribute {
+    Int(i64),
+    Ints(Vec<i64>),
+}
+
+/// A single operator in the graph.
+#[derive(Clone, Debug)]
+struct Node {
+    op_type: String,
+    inputs: Vec<String>,
+    outputs: Vec<String>,
+    attributes: HashMap<String, Attribute>,
+}
+
+impl Node {
+    /// Gets a list of integers, or the default if the attribute is missing.
+    fn ints(&self, name: &str, default: Vec<i64>) -> Result<Vec<i64>, String> {
+        match self.attributes.get(name) {
+            None => Ok(default),
+            Some(Attribute::Ints(ints)) => Ok(ints.clone()),
+            Some(_) => Err(format!("{} expects {} to be a list.", self.op_type, name)),
+        }
+    }
+
+    /// Gets an integer, or the default if the attribute is missing.
+    fn int(&self, name: &str, default: i64) -> Result<i64, String> {
+        match self.attributes.get(name) {
+            None => Ok(default),
+            Some(Attribute::Int(int)) => Ok(*int),
+            Some(_) => Err(format!(
+                "{} expects {} to be an integer.",
+                self.op_type, name
+            )),
+        }
+    }
+}
+
+/// A vision model in ONNX format.
+/// Only the operators used by the models exported by `train` are supported:
+/// `Conv`, `Relu`, `Gemm`, `Reshape`, and `Transpose`.
+#[derive(Clone, Debug, Default)]
+pub struct OnnxModel {
+    nodes: Vec<Node>,
+    weights: HashMap<String, OnnxTensor>,
+    shapes: HashMap<String, Vec<i64>>,
+    input: String,
+    output: String,
+    /// The metadata stored in the model file.
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl OnnxModel {
+    /// Creates an empty graph with a single input.
+    pub fn new(input: &str) -> Self {
+        Self {
+            input: input.to_string(),
+            output: input.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Adds a float initializer to the graph, returning its name.
+    pub fn add_weight(&mut self, tensor: OnnxTensor) -> String {
+        let name = format!("weight{}", self.weights.len());
+        self.weights.insert(name.clone(), tensor);
+        name
+    }
+
+    /// Adds an integer initializer to the graph, for operators that take a shape, returning its name.
+    pub fn add_shape(&mut self, shape: Vec<i64>) -> String {
+        let name = format!("shape{}", self.shapes.len());
+        self.shapes.insert(name.clone(), shape);
+        name
+    }
+
+    /// Adds an operator to the graph, returning the name of its output.
+    /// The output of the last operator is the output of the graph.
+    pub fn add_node(
+        &mut self,
+        op_type: &str,
+        inputs: &[&str],
+        attributes: Vec<(&str, Attribute)>,
+    ) -> String {
+        let output = format!("{}{}", op_type.to_lowercase(), self.nodes.len());
+        self.nodes.push(Node {
+            op_type: op_type.to_string(),
+            inputs: inputs.iter().map(|input| input.to_string()).collect(),
+            outputs: vec![output.clone()],
+            attributes: attributes
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+        });
+        self.output = output.clone();
+        output
+    }
+
+    /// Loads a model from an ONNX file.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let bytes = fs::read(path).map_err(|e| e.to_string())?;
+        Self::from_bytes(&bytes).map_err(|e| format!("The file is not a valid ONNX model. {}", e))
+    }
+
+    /// Saves the model to an ONNX file.
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        fs::write(path, self.to_bytes()).map_err(|e| e.to_string())
+    }
+
+    /// Runs the model on an input.
+    pub fn run(&self, input: OnnxTensor) -> Result<OnnxTensor, String> {
+        let mut values = HashMap::from([(self.input.clone(), input)]);
+        for node in &self.nodes {
+            let get = |index: usize| -> Result<&OnnxTensor, String> {
+                let name = node
+                    .inputs
+                    .get(index)
+                    .ok_or_else(|| format!("{} is missing input {}.", node.op_type, index))?;
+                values
+                    .get(name)
+                    .or_else(|| self.weights.get(name))
+                    .ok_or_else(|| format!("Unknown value {}.", name))
+            };
+            let output = match node.op_type.as_str() {
+                "Conv" => {
+                    let bias = if node.inputs.len() > 2 {
+                        Some(get(2)?)
+                    } else {
+                        None
+                    };
+                    if node.int("group", 1)? != 1 {
+                        return Err("Grouped convolutions are not supported.".to_string());
+                    }
+                    conv(
+                        get(0)?,
+                        get(1)?,
+                        bias,
+                        &node.ints("strides", vec![1, 1])?,
+                        &node.ints("pads", vec![0, 0, 0, 0])?,
+                    )?
+                }
+                "Relu" => {
+                    let mut x = get(0)?.clone();
+                    x.data.iter_mut().for_each(|v| *v = v.max(0.0));
+                    x
+                }
+                "Gemm" => gemm(
+                    get(0)?,
+                    get(1)?,
+                    get(2)?,
+                    node.int("transA", 0)? != 0,
+                    node.int("transB", 0)? != 0,
+                )?,
+                "Reshape" => {
+                    let shape = node
+                        .inputs
+                        .get(1)
+                        .and_then(|name| self.shapes.get(name))
+                        .ok_or("Reshape needs a constant shape.")?;
+                    reshape(get(0)?, shape)?
+                }
+                "Transpose" => transpose(get(0)?, &node.ints("perm", vec![])?)?,
+                op_type => return Err(format!("The {} operator is not supported.", op_type)),
+            };
+            values.insert(node.outputs[0].clone(), output);
+        }
+        values
+            .remove(&self.output)
+            .ok_or_else(|| format!("Unknown value {}.", self.output))
+    }
+
+    /// Serializes the model in the ONNX protobuf format.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut model = Writer::default();
+        model.int(1, IR_VERSION);
+        model.string(2, "saigo");
+        model.message(7, |graph| {
+            for node in &self.nodes {
+                graph.message(1, |n| {
+                    for input in &node.inputs {
+                        n.string(1, input);
+                    }
+                    for output in &node.outputs {
+                        n.string(2, output);
+                    }
+                    n.string(4, &node.op_type);
+                    let mut attributes: Vec<_> = node.attributes.iter().collect();
+                    attributes.sort_by_key(|(name, _)| name.as_str());
+                    for (name, value) in attributes {
+                        n.message(5, |a| {
+                            a.string(1, name);
+                            match value {
+                                Attribute::Int(int) => {
+                                    a.int(3, *int);
+                                    a.int(20, ATTRIBUTE_INT);
+                                }
+                                Attribute::Ints(ints) => {
+                                    for int in ints {
+                                        a.int(8, *int);
+                                    }
+                                    a.int(20, ATTRIBUTE_INTS);
+                                }
+                            }
+                        });
+                    }
+                });
+            }
+            graph.string(2, "saigo");
+            let mut weights: Vec<_> = self.weights.iter().collect();
+            weights.sort_by_key(|(name, _)| name.as_str());
+            for (name, tensor) in weights {
+                graph.message(5, |t| {
+                    for dim in &tensor.shape {
+                        t.int(1, *dim as i64);
+                    }
+                    t.int(2, FLOAT);
+                    t.string(8, name);
+                    t.bytes(
+                        9,
+                        &tensor
+                            .data
+                            .iter()
+                            .flat_map(|v| v.to_le_bytes())
+                            .collect::<Vec<_>>(),
+                    );
+                });
+            }
+            let mut shapes: Vec<_> = self.shapes.iter().collect();
+            shapes.sort_by_key(|(name, _)| name.as_str());
+            for (name, shape) in shapes {
+                graph.message(5, |t| {
+                    t.int(1, shape.len() as i64);
+                    t.int(2, INT64);
+                    t.string(8, name);
+                    t.bytes(
+                        9,
+                        &shape
+                            .iter()
+                            .flat_map(|v| v.to_le_bytes())
+                            .collect::<Vec<_>>(),
+                    );
+                });
+            }
+            // The sizes of the inputs and outputs aren't fixed, so they are left out
+            graph.message(11, |v| {
+                v.string(1, &self.input);
+                v.message(2, |t| t.message(1, |t| t.int(1, FLOAT)));
+            });
+            graph.message(12, |v| {
+                v.string(1, &self.output);
+                v.message(2, |t| t.message(1, |t| t.int(1, FLOAT)));
+            });
+        });
+        model.message(8, |opset| {
+            opset.string(1, "");
+            opset.int(2, OPSET_VERSION);
+        });
+        for (key, value) in &self.metadata {
+            model.message(14, |entry| {
+                entry.string(1, key);
+                entry.string(2, value);
+            });
+        }
+        model.0
+    }
+
+    /// Deserializes a model in the ONNX protobuf format.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let mut model = Self::default();
+        for field in Reader(bytes) {
+            match field? {
+                (7, Value::Bytes(graph)) => model.read_graph(graph)?,
+                (14, Value::Bytes(entry)) => {
+                    let mut key = String::new();
+                    let mut value = String::new();
+                    for field in Reader(entry) {
+                        match field? {
+                            (1, Value::Bytes(bytes)) => key = read_string(bytes)?,
+                            (2, Value::Bytes(bytes)) => value = read_string(bytes)?,
+                            _ => {}
+                        }
+                    }
+                    model.metadata.insert(key, value);
+                }
+                _ => {}
+            }
+        }
+        if model.input.is_empty() || model.output.is_empty() {
+            return Err("The model has no input or output.".to_string());
+        }
+        model.validate()?;
+        Ok(model)
+    }
+
+    /// Checks the attributes and weights of each operator that don't depend on the input,
+    /// so that a broken file is rejected when it is loaded rather than when it is run.
+    fn validate(&self) -> Result<(), String> {
+        for node in &self.nodes {
+            if node.outputs.is_empty() {
+                return Err(format!("{} has no output.", node.op_type));
+            }
+            match node.op_type.as_str() {
+                "Conv" => {
+                    if node.int("group", 1)? != 1 {
+                        return Err("Grouped convolutions are not supported.".to_string());
+                    }
+                    let strides = node.ints("strides", vec![1, 1])?;
+                    if strides.len() != 2 || strides.iter().any(|&stride| stride < 1) {
+                        return Err(format!("Conv has invalid strides {:?}.", strides));
+                    }
+                    let pads = node.ints("pads", vec![0, 0, 0, 0])?;
+                    if pads.len() != 4 || pads.iter().any(|&pad| pad < 0) {
+                        return Err(format!("Conv has invalid pads {:?}.", pads));
+                    }
+                    let weight = node
+                        .inputs
+                        .get(1)
+                        .and_then(|name| self.weights.get(name))
+                        .ok_or("Conv needs constant weights.")?;
+                    let [m, _, _, _] = weight.shape4()?;
+                    if let Some(name) = node.inputs.get(2) {
+                        let bias = self
+                            .weights
+                            .get(name)
+                            .ok_or("Conv needs a constant bias.")?;
+                        if bias.data.len() != m {
+                            return Err(format!(
+                                "Conv has {} outputs but a bias of shape {:?}.",
+                                m, bias.shape
+                            ));
+                        }
+                    }
+                }
+                "Reshape" => {
+                    let shape = node
+                        .inputs
+                        .get(1)
+                        .and_then(|name| self.shapes.get(name))
+                        .ok_or("Reshape needs a constant shape.")?;
+                    if shape.iter().any(|&dim| dim < -1)
+                        || shape.iter().filter(|&&dim| dim == -1).count() > 1
+                    {
+                        return Err(format!("Reshape has an invalid shape {:?}.", shape));
+                    }
+                }
+                "Relu" | "Gemm" | "Transpose" => {}
+                op_type => return Err(format!("The {} operator is not supported.", op_type)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the nodes, initializers, input, and output of a graph.
+    fn read_graph(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let mut inputs = Vec::new();
+        for field in Reader(bytes) {
+            match field? {
+                (1, Value::Bytes(node)) => self.nodes.push(read_node(node)?),
+                (5, Value::Bytes(tensor)) => self.read_initializer(tensor)?,
+                (11, Value::Bytes(value_info)) => inputs.push(read_value_info_name(value_info)?),
+                (12, Value::Bytes(value_info)) => self.output = read_value_info_name(value_info)?,
+                _ => {}
+            }
+        }
+        // Some exporters also list the initializers as inputs
+        self.input = inputs
+            .into_iter()
+            .find(|name| !self.weights.contains_key(name) && !self.shapes.contains_key(name))
+            .unwrap_or_default();
+        Ok(())
+    }
+
+    /// Reads an initializer, which can be either floats or integers.
+    fn read_initializer(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let mut shape = Vec::new();
+        let mut data_type = 0;
+        let mut name = String::new();
+        let mut raw_data: &[u8] = &[];
+        let mut floats = Vec::new();
+        let mut ints = Vec::new();
+        for field in Reader(bytes) {
+            match field? {
+                (1, value) => shape.extend(read_varints(value)?),
+                (2, Value::Varint(value)) => data_type = value as i64,
+                (4, Value::Bytes(bytes)) => floats.extend(
+                    bytes
+                        .chunks_exact(4)
+                        .map(|b| f32::from_le_bytes(b.try_into().unwrap())),
+                ),
+                (4, Value::Fixed32(value)) => floats.push(f32::from_bits(value)),
+                (7, value) => ints.extend(read_varints(value)?),
+                (8, Value::Bytes(bytes)) => name = read_string(bytes)?,
+                (9, Value::Bytes(bytes)) => raw_data = bytes,
+                _ => {}
+            }
+        }
+        match data_type {
+            FLOAT => {
+                if !raw_data.is_empty() {
+                    floats = raw_data
+                        .chunks_exact(4)
+                        .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+                        .collect();
+                }
+                let shape = shape
+                    .into_iter()
+                    .map(usize::try_from)
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| format!("{} has a negative dimension.", name))?;
+                self.weights.insert(name, OnnxTensor::new(shape, floats)?);
+            }
+            INT64 => {
+                if !raw_data.is_empty() {
+                    ints = raw_data
+                        .chunks_exact(8)
+                        .map(|b| i64::from_le_bytes(b.try_into().unwrap()))
+                        .collect();
+                }
+                self.shapes.insert(name, ints);
+            }
+            _ => return Err(format!("The data type of {} is not supported.", name)),
+        }
+        Ok(())
+    }
+}
+
+/// Reads an operator from the graph.
+fn read_node(bytes: &[u8]) -> Result<Node, String> {
+    let mut node = Node {
+        op_type: String::new(),
+        inputs: Vec::new(),
+        outputs: Vec::new(),
+        attributes: HashMap::new(),
+    };
+    for field in Reader(bytes) {
+        match field? {
+            (1, Value::Bytes(bytes)) => node.inputs.push(read_string(bytes)?),
+            (2, Value::Bytes(bytes)) => node.outputs.push(read_string(bytes)?),
+            (4, Value::Bytes(bytes)) => node.op_type = read_string(bytes)?,
+            (5, Value::Bytes(attribute)) => {
+                let mut name = String::new();
+                let mut int = None;
+                let mut ints = Vec::new();
+                let mut attribute_type = 0;
+                for field in Reader(attribute) {
+                    match field? {
+                        (1, Value::Bytes(bytes)) => name = read_string(bytes)?,
+                        (3, Value::Varint(value)) => int = Some(value as i64),
+                        (8, value) => ints.extend(read_varints(value)?),
+                        (20, Value::Varint(value)) => attribute_type = value as i64,
+                        _ => {}
+                    }
+                }
+                // Attributes of other types aren't used by any supported operator
+                match (attribute_type, int) {
+                    (ATTRIBUTE_INT, Some(int)) => {
+                        node.attributes.insert(name, Attribute::Int(int));
+                    }
+                    (ATTRIBUTE_INTS, _) => {
+                        node.attributes.insert(name, Attribute::Ints(ints));
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    if node.outputs.is_empty() {
+        return Err(format!("The {} operator has no output.", node.op_type));
+    }
+    Ok(node)
+}
+
+/// Reads the name of an input or output of the graph.
+fn read_value_info_name(bytes: &[u8]) -> Result<String, String> {
+    for field in Reader(bytes) {
+        if let (1, Value::Bytes(bytes)) = field? {
+            return read_string(bytes);
+        }
+    }
+    Err("An input or output has no name.".to_string())
+}
+
+/// Reads a string field.
+fn read_string(bytes: &[u8]) -> Result<String, String> {
+    String::from_utf8(bytes.to_vec()).map_err(|e| e.to_string())
+}
+
+/// Reads a repeated integer field, which can be either packed or not.
+fn read_varints(value: Value) -> Result<Vec<i64>, String> {
+    match value {
+        Value::Varint(value) => Ok(vec![value as i64]),
+        Value::Bytes(mut bytes) => {
+            let mut values = Vec::new();
+            while !bytes.is_empty() {
+                values.push(read_varint(&mut bytes)? as i64);
+            }
+            Ok(values)
+        }
+        _ => Err("Expected an integer.".to_string()),
+    }
+}
+
+/// Reads a variable length integer from the start of the bytes.
+fn read_varint(bytes: &mut &[u8]) -> Result<u64, String> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = bytes.split_first().ok_or("Unexpected end of data.")?;
+        *bytes = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err("An integer is too long.".to_string())
+}
+
+/// A protobuf field value.
+enum Value<'a> {
+    Varint(u64),
+    Fixed64,
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+/// Iterates over the fields of a protobuf message.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Iterator for Reader<'a> {
+    type Item = Result<(u64, Value<'a>), String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0.is_empty() {
+            return None;
+        }
+        let mut read = || {
+            let key = read_varint(&mut self.0)?;
+            let take = |bytes: &mut &'a [u8], n: usize| -> Result<&'a [u8], String> {
+                if bytes.len() < n {
+                    return Err("Unexpected end of data.".to_string());
+                }
+                let (value, rest) = bytes.split_at(n);
+                *bytes = rest;
+                Ok(value)
+            };
+            let value = match key & 7 {
+                0 => Value::Varint(read_varint(&mut self.0)?),
+                1 => {
+                    take(&mut self.0, 8)?;
+                    Value::Fixed64
+                }
+                2 => {
+                    let length = read_varint(&mut self.0)? as usize;
+                    Value::Bytes(take(&mut self.0, length)?)
+                }
+                5 => Value::Fixed32(u32::from_le_bytes(
+                    take(&mut self.0, 4)?.try_into().unwrap(),
+                )),
+                wire_type => return Err(format!("Unsupported wire type {}.", wire_type)),
+            };
+            Ok((key >> 3, value))
+        };
+        let result = read();
+        // Stop after an error, since the rest of the data can't be trusted
+        if result.is_err() {
+            self.0 = &[];
+        }
+        Some(result)
+    }
+}
+
+/// Builds a protobuf message.
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn int(&mut self, field: u64, value: i64) {
+        self.varint(field << 3);
+        self.varint(value as u64);
+    }
+
+    fn bytes(&mut self, field: u64, bytes: &[u8]) {
+        self.varint(field << 3 | 2);
+        self.varint(bytes.len() as u64);
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn string(&mut self, field: u64, value: &str) {
+        self.bytes(field, value.as_bytes());
+    }
+
+    fn message(&mut self, field: u64, build: impl FnOnce(&mut Writer)) {
+        let mut message = Writer::default();
+        build(&mut message);
+        self.bytes(field, &message.0);
+    }
+}
+
+/// Runs a two-dimensional convolution.
+fn conv(
+    x: &OnnxTensor,
+    w: &OnnxTensor,
+    b: Option<&OnnxTensor>,
+    strides: &[i64],
+    pads: &[i64],
+) -> Result<OnnxTensor, String> {
+    let [n, c, h, width] = x.shape4()?;
+    let [m, wc, kh, kw] = w.shape4()?;
+    let (&[sy, sx], &[top, left, bottom, right]) = (strides, pads) else {
+        return Err("Conv expects two strides and four pads.".to_string());
+    };
+    let to_usize = |value: i64| usize::try_from(value).map_err(|e| e.to_string());
+    let (sy, sx) = (to_usize(sy)?, to_usize(sx)?);
+    let (top, left, bottom, right) = (
+        to_usize(top)?,
+        to_usize(left)?,
+        to_usize(bottom)?,
+        to_usize(right)?,
+    );
+    if sy == 0 || sx == 0 {
+        return Err("Conv expects strides of at least 1.".to_string());
+    }
+    if wc != c
+        || h + top + bottom < kh
+        || width + left + right < kw
+        || b.is_some_and(|b| b.data.len() != m)
+    {
+        return Err(format!(
+            "Can't convolve an input of shape {:?} with weights of shape {:?}.",
+            x.shape, w.shape
+        ));
+    }
+    let oh = (h + top + bottom - kh) / sy + 1;
+    let ow = (width + left + right - kw) / sx + 1;
+
+    let mut out = vec![0.0; n * m * oh * ow];
+    for (batch, out) in out.chunks_exact_mut(m * oh * ow).enumerate() {
+        for (o, plane) in out.chunks_exact_mut(oh * ow).enumerate() {
+            plane.fill(b.map_or(0.0, |b| b.data[o]));
+            for i in 0..c {
+                let input = &x.data[(batch * c + i) * h * width..][..h * width];
+                for ky in 0..kh {
+                    for kx in 0..kw {
+                        let weight = w.data[((o * c + i) * kh + ky) * kw + kx];
+                        // Only the columns that fall inside the input rather than in the padding
+                        let first = left.saturating_sub(kx).div_ceil(sx);
+                        let last = (width + left).saturating_sub(kx).div_ceil(sx).min(ow);
+                        if first >= last {
+                            continue;
+                        }
+                        let start = first * sx + kx - left;
+                        for oy in 0..oh {
+                            // Skip the rows that fall in the padding
+                            let Some(iy) = (oy * sy + ky).checked_sub(top).filter(|&iy| iy < h)
+                            else {
+                                continue;
+                            };
+                            let row = &input[iy * width + start..(iy + 1) * width];
+                            let out = &mut plane[oy * ow + first..oy * ow + last];
+                            // Contiguous rows are much faster to add up, and most layers have a stride of 1
+                            if sx == 1 {
+                                for (out, &input) in out.iter_mut().zip(row) {
+                                    *out += weight * input;
+                                }
+                            } else {
+                                for (out, &input) in out.iter_mut().zip(row.iter().step_by(sx)) {
+                                    *out += weight * input;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    OnnxTensor::new(vec![n, m, oh, ow], out)
+}
+
+/// Multiplies two matrices and adds a bias.
+fn gemm(
+    a: &OnnxTensor,
+    b: &OnnxTensor,
+    c: &OnnxTensor,
+    trans_a: bool,
+    trans_b: bool,
+) -> Result<OnnxTensor, String> {
+    let [ar, ac] = a.shape2()?;
+    let [br, bc] = b.shape2()?;
+    let (n, k) = if trans_a { (ac, ar) } else { (ar, ac) };
+    let (bk, m) = if trans_b { (bc, br) } else { (br, bc) };
+    if k != bk || c.data.len() != m {
+        return Err(format!(
+            "Can't multiply matrices of shape {:?} and {:?} with a bias of shape {:?}.",
+            a.shape, b.shape, c.shape
+        ));
+    }
+    let a_at = |i: usize, j: usize| {
+        if trans_a {
+            a.data[j * ac + i]
+        } else {
+            a.data[i * ac + j]
+        }
+    };
+    let b_at = |i: usize, j: usize| {
+        if trans_b {
+            b.data[j * bc + i]
+        } else {
+            b.data[i * bc + j]
+        }
+    };
+    let mut out = Vec::with_capacity(n * m);
+    for row in 0..n {
+        for col in 0..m {
+            out.push(c.data[col] + (0..k).map(|i| a_at(row, i) * b_at(i, col)).sum::<f32>());
+        }
+    }
+    OnnxTensor::new(vec![n, m], out)
+}
+
+/// Changes the shape of a tensor without changing its data.
+fn reshape(x: &OnnxTensor, shape: &[i64]) -> Result<OnnxTensor, String> {
+    let mut inferred = None;
+    let mut new_shape = Vec::with_capacity(shape.len());
+    for (i, &dim) in shape.iter().enumerate() {
+        match dim {
+            // Zero copies the dimension from the input
+            0 => new_shape.push(x.shape.get(i).copied().unwrap_or(0)),
+            // Negative one is inferred from the size of the input
+            -1 => {
+                inferred = Some(i);
+                new_shape.push(1);
+            }
+            dim => new_shape.push(
+                usize::try_from(dim)
+                    .map_err(|_| format!("Can't reshape a tensor to {:?}.", shape))?,
+            ),
+        }
+    }
+    if let Some(i) = inferred {
+        let known = new_shape
+            .iter()
+            .try_fold(1usize, |known, &dim| known.checked_mul(dim))
+            .unwrap_or(0);
+        if known == 0 || !x.data.len().is_multiple_of(known) {
+            return Err(format!(
+                "Can't reshape a tensor of shape {:?} to {:?}.",
+                x.shape, shape
+            ));
+        }
+        new_shape[i] = x.data.len() / known;
+    }
+    OnnxTensor::new(new_shape, x.data.clone())
+}
+
+/// Reorders the dimensions of a tensor.
+fn transpose(x: &OnnxTensor, perm: &[i64]) -> Result<OnnxTensor, String> {
+    let dims = x.shape.len();
+    let perm: Vec<usize> = if perm.is_empty() {
+        (0..dims).rev().collect()
+    } else {
+        perm.iter().map(|&p| p as usize).collect()
+    };
+    let mut sorted = perm.clone();
+    sorted.sort();
+    if sorted != (0..dims).collect::<Vec<_>>() {
+        return Err(format!(
+            "Can't transpose a tensor of shape {:?} by {:?}.",
+            x.shape, perm
+        ));
+    }
+
+    let mut strides = vec![1; dims];
+    for i in (0..dims.saturating_sub(1)).rev() {
+        strides[i] = strides[i + 1] * x.shape[i + 1];
+    }
+    let shape: Vec<_> = perm.iter().map(|&p| x.shape[p]).collect();
+    let mut out = Vec::with_capacity(x.data.len());
+    let mut index = vec![0; dims];
+    for _ in 0..x.data.len() {
+        out.push(
+            x.data[index
+                .iter()
+                .zip(&perm)
+                .map(|(i, &p)| i * strides[p])
+                .sum::<usize>()],
+        );
+        // Count up through the output indexes in row-major order
+        for d in (0..dims).rev() {
+            index[d] += 1;
+            if index[d] < shape[d] {
+                break;
+            }
+            index[d] = 0;
+        }
+    }
+    OnnxTensor::new(shape, out)
+}
+
+/// Runs a vision model exported to ONNX as a [`BoardRecognizer`], without LibTorch.
+pub struct OnnxRecognizer {
+    model: OnnxModel,
+    format: ModelFormat,
+}
+
+impl OnnxRecognizer {
+    /// Loads the model from a file, checking that it produces the four classification values.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let model = OnnxModel::load(path)?;
+        let format = check_metadata(&model.metadata)?;
+
+        // Make sure the model produces an output for each of the four classes
+        let planes = format.input_planes.count();
+        let input = OnnxTensor::new(
+            vec![1, planes, STONE_SIZE as usize, STONE_SIZE as usize],
+            vec![0.0; planes * (STONE_SIZE * STONE_SIZE) as usize],
+        )?;
+        let output = model.run(input)?;
+        if output.shape != [1, 4] {
+            return Err(format!(
+                "Expected an output of shape [1, 4], but got {:?}.",
+                output.shape
+            ));
+        }
+
+        Ok(Self { model, format })
+    }
+
+    /// Returns the metadata stored in the model file.
+    pub fn metadata(&self) -> &BTreeMap<String, String> {
+        &self.model.metadata
+    }
+
+    /// Returns the number of trained parameters in the model.
+    pub fn parameters(&self) -> i64 {
+        self.model
+            .weights
+            .values()
+            .map(|weight| weight.data.len() as i64)
+            .sum()
+    }
+
+    /// Runs the model on a batch of inputs, returning the probabilities for each of the expected number of output rows.
+    /// The model was only checked on a single crop when it was loaded, so other inputs can still fail.
+    fn run(&self, input: OnnxTensor, rows: usize) -> Result<Vec<VisionModelOutput>, String> {
+        let output = self.model.run(input)?;
+        if output.data.len() != rows * 4 {
+            return Err(format!(
+                "Expected {} outputs of 4 scores, but got an output of shape {:?}.",
+                rows, output.shape
+            ));
+        }
+        Ok(output.data.chunks_exact(4).map(softmax).collect())
+    }
+}
+
+impl BoardRecognizer for OnnxRecognizer {
+    fn recognize(
+        &self,
+        image: &Rgb32FImage,
+        reference: &Rgb32FImage,
+        display: &Rgb32FImage,
+    ) -> Result<Vec<Vec<VisionModelOutput>>, String> {
+        let width = reference.width() / STONE_SIZE;
+        let height = reference.height() / STONE_SIZE;
+        let images = self.format.input_images(image, reference, display);
+        let input = match self.format.architecture {
+            Architecture::Crop => {
+                // One batch entry per intersection, in row-major order
+                let intersections: Vec<_> = (0..height)
+                    .flat_map(|y| (0..width).map(move |x| (x, y)))
+                    .collect();
+                read_crops(&images, &intersections)
+            }
+            Architecture::Board => read_board(&images),
+        };
+
+        Ok(self
+            .run(input, (width * height) as usize)?
+            .chunks_exact(width as usize)
+            .map(|row| row.to_vec())
+            .collect())
+    }
+
+    fn recognize_intersections(
+        &self,
+        image: &Rgb32FImage,
+        reference: &Rgb32FImage,
+        display: &Rgb32FImage,
+        intersections: &[(u32, u32)],
+    ) -> Result<Vec<VisionModelOutput>, String> {
+        // Whole-board models need the surrounding intersections, so only crops can be classified separately
+        if self.format.architecture != Architecture::Crop {
+            return Ok(pick_intersections(
+                &self.recognize(image, reference, display)?,
+                intersections,
+            ));
+        }
+
+        let images = self.format.input_images(image, reference, display);
+        self.run(read_crops(&images, intersections), intersections.len())
+    }
+
+    fn recognizes_intersections_separately(&self) -> bool {
+        self.format.architecture == Architecture::Crop
+    }
+
+    fn needs_reference(&self) -> bool {
+        self.format.input_planes.reference
+    }
+}
+
+/// Constructs a batch of stone-sized crops around the given intersections,
+/// with the same layout as the input of the LibTorch models.
+fn read_crops(images: &[&Rgb32FImage], intersections: &[(u32, u32)]) -> OnnxTensor {
+    let s = STONE_SIZE as usize;
+    let planes = images.len() * 3;
+    let mut data = Vec::with_capacity(intersections.len() * planes * s * s);
+    for &(x, y) in intersections {
+        let (x0, y0) = (x as usize * s, y as usize * s);
+        for image in images {
+            // Read the pixels straight from the interleaved buffer, one row of the crop at a time
+            let width = image.width() as usize;
+            let raw = image.as_raw();
+            for c in 0..3 {
+                for y in y0..y0 + s {
+                    let row = &raw[(y * width + x0) * 3..(y * width + x0 + s) * 3];
+                    data.extend(row.iter().skip(c).step_by(3));
+                }
+            }
+        }
+    }
+    OnnxTensor::new(vec![intersections.len(), planes, s, s], data).unwrap()
+}
+
+/// Constructs the input for the whole board, with the same layout as the input of the LibTorch models.
+fn read_board(images: &[&Rgb32FImage]) -> OnnxTensor {
+    let (w, h) = (images[0].width() as usize, images[0].height() as usize);
+    let planes = images.len() * 3;
+    let mut data = Vec::with_capacity(planes * w * h);
+    for image in images {
+        for c in 0..3 {
+            data.extend(image.pixels().map(|pixel| pixel[c]));
+        }
+    }
+    OnnxTensor::new(vec![1, planes, h, w], data).unwrap()
+}
+
+/// Converts the model's scores to probabilities that add up to 1.
+fn softmax(scores: &[f32]) -> VisionModelOutput {
+    let max = scores.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let e: Vec<_> = scores.iter().map(|score| (score - max).exp()).collect();
+    let sum: f32 = e.iter().sum();
+    (e[0] / sum, e[1] / sum, e[2] / sum, e[3] / sum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a small model with each supported operator.
+    fn small_model() -> OnnxModel {
+        let mut graph = OnnxModel::new("input");
+        let weight = graph.add_weight(OnnxTensor::new(vec![2, 3, 3, 3], vec![0.1; 54]).unwrap());
+        let bias = graph.add_weight(OnnxTensor::new(vec![2], vec![0.5, -0.5]).unwrap());
+        let xs = graph.add_node(
+            "Conv",
+            &["input", &weight, &bias],
+            vec![
+                ("strides", Attribute::Ints(vec![1, 1])),
+                ("pads", Attribute::Ints(vec![1, 1, 1, 1])),
+            ],
+        );
+        let xs = graph.add_node("Relu", &[&xs], vec![]);
+        let xs = graph.add_node(
+            "Transpose",
+            &[&xs],
+            vec![("perm", Attribute::Ints(vec![0, 2, 3, 1]))],
+        );
+        let shape = graph.add_shape(vec![-1, 2]);
+        graph.add_node("Reshape", &[&xs, &shape], vec![]);
+        graph
+            .metadata
+            .insert("key".to_string(), "value".to_string());
+        graph
+    }
+
+    fn input() -> OnnxTensor {
+        let data = (0..48).map(|i| i as f32 / 48.0).collect();
+        OnnxTensor::new(vec![1, 3, 4, 4], data).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let model = small_model();
+        let loaded = OnnxModel::from_bytes(&model.to_bytes()).unwrap();
+        assert_eq!(loaded.metadata, model.metadata);
+        let expected = model.run(input()).unwrap();
+        let actual = loaded.run(input()).unwrap();
+        assert_eq!(actual.shape, [16, 2]);
+        assert_eq!(actual.data, expected.data);
+    }
+
+    #[test]
+    fn truncated_bytes() {
+        let bytes = small_model().to_bytes();
+        for len in 0..bytes.len() {
+            // Any result is fine as long as it doesn't panic, and a model that loads must run or fail cleanly
+            if let Ok(model) = OnnxModel::from_bytes(&bytes[..len]) {
+                let _ = model.run(input());
+            }
+        }
+    }
+
+    #[test]
+    fn garbage_bytes() {
+        assert!(OnnxModel::from_bytes(&[0xff]).is_err());
+        assert!(OnnxModel::from_bytes(&[0x3a, 0xff, 0xff, 0xff, 0xff, 0x0f]).is_err());
+        // A simple linear congruential generator, so that the test is repeatable
+        let mut state = 1u32;
+        for _ in 0..1000 {
+            let bytes: Vec<u8> = (0..64)
+                .map(|_| {
+                    state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                    (state >> 24) as u8
+                })
+                .collect();
+            if let Ok(model) = OnnxModel::from_bytes(&bytes) {
+                let _ = model.run(input());
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_attributes() {
+        for (name, value) in [
+            ("strides", vec![0, 1]),
+            ("strides", vec![1]),
+            ("pads", vec![-1, 0, 0, 0]),
+            ("pads", vec![0, 0]),
+        ] {
+            let mut model = small_model();
+            model.nodes[0]
+                .attributes
+                .insert(name.to_string(), Attribute::Ints(value));
+            assert!(OnnxModel::from_bytes(&model.to_bytes()).is_err());
+        }
+
+        let mut model = small_model();
+        let bias = model.nodes[0].inputs[2].clone();
+        model
+            .weights
+            .insert(bias, OnnxTensor::new(vec![1], vec![0.0]).unwrap());
+        assert!(OnnxModel::from_bytes(&model.to_bytes()).is_err());
+
+        let mut model = small_model();
+        let shape = model.nodes[3].inputs[1].clone();
+        model.shapes.insert(shape, vec![-2, 2]);
+        assert!(OnnxModel::from_bytes(&model.to_bytes()).is_err());
+    }
+
+    /// Convolves by the definition, reading zero for positions in the padding.
+    fn naive_conv(
+        x: &OnnxTensor,
+        w: &OnnxTensor,
+        b: &OnnxTensor,
+        (sy, sx): (usize, usize),
+        [top, left, bottom, right]: [usize; 4],
+    ) -> OnnxTensor {
+        let [n, c, h, width] = x.shape4().unwrap();
+        let [m, _, kh, kw] = w.shape4().unwrap();
+        let oh = (h + top + bottom - kh) / sy + 1;
+        let ow = (width + left + right - kw) / sx + 1;
+        let at = |batch: usize, i: usize, y: isize, x_: isize| {
+            if y < 0 || x_ < 0 || y as usize >= h || x_ as usize >= width {
+                0.0
+            } else {
+                x.data[((batch * c + i) * h + y as usize) * width + x_ as usize]
+            }
+        };
+        let mut out = vec![];
+        for batch in 0..n {
+            for o in 0..m {
+                for oy in 0..oh {
+                    for ox in 0..ow {
+                        let mut sum = b.data[o];
+                        for i in 0..c {
+                            for ky in 0..kh {
+                                for kx in 0..kw {
+                                    let y = (oy * sy + ky) as isize - top as isize;
+                                    let x_ = (ox * sx + kx) as isize - left as isize;
+                                    sum += w.data[((o * c + i) * kh + ky) * kw + kx]
+                                        * at(batch, i, y, x_);
+                                }
+                            }
+                        }
+                        out.push(sum);
+                    }
+                }
+            }
+        }
+        OnnxTensor::new(vec![n, m, oh, ow], out).unwrap()
+    }
+
+    /// Fills a tensor with varied values that are the same every time.
+    fn tensor(shape: Vec<usize>, seed: usize) -> OnnxTensor {
+        let size = shape.iter().product();
+        let data = (0..size)
+            .map(|i| ((i * 7919 + seed * 104_729) % 211) as f32 / 105.0 - 1.0)
+            .collect();
+        OnnxTensor::new(shape, data).unwrap()
+    }
+
+    #[test]
+    fn conv_matches_definition() {
+        let x = tensor(vec![2, 3, 11, 9], 1);
+        let w = tensor(vec![4, 3, 3, 2], 2);
+        let b = tensor(vec![4], 3);
+        for (strides, pads) in [
+            ((1, 1), [0, 0, 0, 0]),
+            ((1, 1), [1, 1, 1, 1]),
+            ((2, 3), [0, 0, 0, 0]),
+            ((2, 3), [2, 0, 1, 3]),
+            ((3, 2), [0, 1, 2, 0]),
+        ] {
+            let expected = naive_conv(&x, &w, &b, strides, pads);
+            let actual = conv(
+                &x,
+                &w,
+                Some(&b),
+                &[strides.0 as i64, strides.1 as i64],
+                &pads.map(|pad| pad as i64),
+            )
+            .unwrap();
+            assert_eq!(actual.shape, expected.shape, "{:?} {:?}", strides, pads);
+            for (a, e) in actual.data.iter().zip(&expected.data) {
+                assert!(
+                    (a - e).abs() < 1e-4,
+                    "{:?} {:?}: {} != {}",
+                    strides,
+                    pads,
+                    a,
+                    e
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn oversized_shape() {
+        assert!(OnnxTensor::new(vec![usize::MAX, 2], vec![]).is_err());
+        assert!(OnnxTensor::new(vec![usize::MAX, 0], vec![]).is_ok());
+    }
+}
@@ -0,0 +1,229 @@
+use image::{Rgb, Rgb32FImage};
+use serde::{Deserialize, Serialize};
+
+use crate::STONE_SIZE;
+
+/// The probabilities of no stone, black stone, white stone, and obscured at an intersection, in that order.
+pub type VisionModelOutput = (f32, f32, f32, f32);
+
+/// Reads the state of each intersection from the normalized image of the board.
+pub trait BoardRecognizer: Send {
+    /// Returns the probabilities for each intersection in row-major order,
+    /// given the board image, a reference image of the empty board of the same size,
+    /// and the image that the display is projecting onto the board, mapped to the same size.
+    /// Fails if the model can't be run on the images, such as when a loaded model file is broken.
+    fn recognize(
+        &self,
+        image: &Rgb32FImage,
+        reference: &Rgb32FImage,
+        display: &Rgb32FImage,
+    ) -> Result<Vec<Vec<VisionModelOutput>>, String>;
+
+    /// Returns the probabilities for only the given intersections, as (x, y) coordinates, in the same order.
+    /// Recognizers that can't classify intersections separately recognize the whole board.
+    fn recognize_intersections(
+        &self,
+        image: &Rgb32FImage,
+        reference: &Rgb32FImage,
+        display: &Rgb32FImage,
+        intersections: &[(u32, u32)],
+    ) -> Result<Vec<VisionModelOutput>, String> {
+        Ok(pick_intersections(
+            &self.recognize(image, reference, display)?,
+            intersections,
+        ))
+    }
+
+    /// Returns whether the recognizer can classify intersections separately,
+    /// so that recognizing only some of them is cheaper than recognizing the whole board.
+    fn recognizes_intersections_separately(&self) -> bool {
+        false
+    }
+
+    /// Returns whether the recognizer compares the board to the reference image.
+    /// Recognizers that don't can run before a reference image is taken, given a blank one.
+    fn needs_reference(&self) -> bool {
+        true
+    }
+}
+
+/// Selects the probabilities of the given intersections, as (x, y) coordinates, from those of the whole board.
+pub fn pick_intersections(
+    probabilities: &[Vec<VisionModelOutput>],
+    intersections: &[(u32, u32)],
+) -> Vec<VisionModelOutput> {
+    intersections
+        .iter()
+        .map(|&(x, y)| probabilities[y as usize][x as usize])
+        .collect()
+}
+
+/// The kinds of recognizer that can be selected.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecognizerKind {
+    /// The neural network vision model, which requires LibTorch or a model exported to ONNX.
+    Model,
+    /// Compares the color and brightness of each intersection to the reference image.
+    Difference,
+}
+
+impl Default for RecognizerKind {
+    fn default() -> Self {
+        if cfg!(any(feature = "tch", feature = "onnx")) {
+            RecognizerKind::Model
+        } else {
+            RecognizerKind::Difference
+        }
+    }
+}
+
+/// The radius around each intersection that is compared, in pixels of the board image.
+const SAMPLE_RADIUS: f32 = STONE_SIZE as f32 * 0.3;
+
+/// How different the color must be from the reference before the intersection is considered occupied.
+const MIN_DIFFERENCE: f32 = 0.08;
+
+/// How much darker or whiter than the reference a stone must be.
+const MIN_CONTRAST: f32 = 0.1;
+
+/// How much the brightness can vary within a stone.
+const MAX_VARIATION: f32 = 0.08;
+
+/// How sharply the probabilities change around the thresholds.
+const SHARPNESS: f32 = 40.0;
+
+/// How bright the display can be at a pixel before the pixel is left out of the comparison.
+pub const MAX_DISPLAY_BRIGHTNESS: f32 = 0.05;
+
+/// Recognizes stones without a neural network, by comparing the center of each intersection to the reference image.
+/// A stone changes the color much more than noise does, and it is told apart from a hand by being uniform.
+/// Pixels lit by the display are left out where possible, but this is still less robust than the vision model.
+#[derive(Default)]
+pub struct DifferenceRecognizer;
+
+impl BoardRecognizer for DifferenceRecognizer {
+    fn recognize(
+        &self,
+        image: &Rgb32FImage,
+        reference: &Rgb32FImage,
+        display: &Rgb32FImage,
+    ) -> Result<Vec<Vec<VisionModelOutput>>, String> {
+        let width = reference.width() / STONE_SIZE;
+        let height = reference.height() / STONE_SIZE;
+        Ok((0..height)
+            .map(|y| {
+                (0..width)
+                    .map(|x| classify(image, reference, display, x * STONE_SIZE, y * STONE_SIZE))
+                    .collect()
+            })
+            .collect())
+    }
+
+    fn recognize_intersections(
+        &self,
+        image: &Rgb32FImage,
+        reference: &Rgb32FImage,
+        display: &Rgb32FImage,
+        intersections: &[(u32, u32)],
+    ) -> Result<Vec<VisionModelOutput>, String> {
+        Ok(intersections
+            .iter()
+            .map(|&(x, y)| classify(image, reference, display, x * STONE_SIZE, y * STONE_SIZE))
+            .collect())
+    }
+
+    fn recognizes_intersections_separately(&self) -> bool {
+        true
+    }
+}
+
+/// Estimates the state of the intersection whose stone-sized area starts at the given location.
+fn classify(
+    image: &Rgb32FImage,
+    reference: &Rgb32FImage,
+    display: &Rgb32FImage,
+    x0: u32,
+    y0: u32,
+) -> VisionModelOutput {
+    let center = (STONE_SIZE as f32 - 1.0) * 0.5;
+    let in_sample = |x: u32, y: u32| {
+        (x as f32 - center).powi(2) + (y as f32 - center).powi(2) <= SAMPLE_RADIUS.powi(2)
+    };
+    // Projected light changes the color just like a stone does, so only compare the unlit pixels,
+    // unless the whole intersection is lit
+    let unlit =
+        |x: u32, y: u32| luminance(display.get_pixel(x0 + x, y0 + y)) <= MAX_DISPLAY_BRIGHTNESS;
+    let any_unlit = (0..STONE_SIZE)
+        .flat_map(|y| (0..STONE_SIZE).map(move |x| (x, y)))
+        .any(|(x, y)| in_sample(x, y) && unlit(x, y));
+    let mut count = 0.0;
+    let mut difference = 0.0;
+    let mut brightness = 0.0;
+    let mut brightness_squared = 0.0;
+    let mut ref_brightness = 0.0;
+    let mut saturation = 0.0;
+    let mut ref_saturation = 0.0;
+    for y in 0..STONE_SIZE {
+        for x in 0..STONE_SIZE {
+            if !in_sample(x, y) || (any_unlit && !unlit(x, y)) {
+                continue;
+            }
+            let pixel = image.get_pixel(x0 + x, y0 + y);
+            let ref_pixel = reference.get_pixel(x0 + x, y0 + y);
+            count += 1.0;
+            difference += (0..3).map(|c| (pixel[c] - ref_pixel[c]).abs()).sum::<f32>() / 3.0;
+            let pixel_brightness = luminance(pixel);
+            brightness += pixel_brightness;
+            brightness_squared += pixel_brightness * pixel_brightness;
+            ref_brightness += luminance(ref_pixel);
+            saturation += pixel_saturation(pixel);
+            ref_saturation += pixel_saturation(ref_pixel);
+        }
+    }
+    difference /= count;
+    brightness /= count;
+    brightness_squared /= count;
+    ref_brightness /= count;
+    saturation /= count;
+    ref_saturation /= count;
+    let variation = (brightness_squared - brightness * brightness)
+        .max(0.0)
+        .sqrt();
+
+    // Score each class, where positive means likely, then convert the scores to probabilities
+    let occupied = (difference - MIN_DIFFERENCE) * SHARPNESS;
+    let uniform = (MAX_VARIATION - variation) * SHARPNESS;
+    let darkness = ref_brightness - brightness;
+    // White stones are brighter than the board, or at least less colorful
+    let whiteness = (brightness - ref_brightness) + (ref_saturation - saturation);
+    let empty = -occupied;
+    let black = occupied
+        .min(uniform)
+        .min((darkness - MIN_CONTRAST) * SHARPNESS);
+    let white = occupied
+        .min(uniform)
+        .min((whiteness - MIN_CONTRAST) * SHARPNESS);
+    let obscured = occupied.min(-black.max(white));
+    softmax([empty, black, white, obscured])
+}
+
+/// Calculates the perceived brightness of a pixel.
+pub fn luminance(pixel: &Rgb<f32>) -> f32 {
+    0.299 * pixel[0] + 0.587 * pixel[1] + 0.114 * pixel[2]
+}
+
+/// Calculates how colorful a pixel is.
+fn pixel_saturation(pixel: &Rgb<f32>) -> f32 {
+    let max = pixel[0].max(pixel[1]).max(pixel[2]);
+    let min = pixel[0].min(pixel[1]).min(pixel[2]);
+    max - min
+}
+
+/// Converts scores to probabilities that add up to 1.
+fn softmax(scores: [f32; 4]) -> VisionModelOutput {
+    let max = scores.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let [a, b, c, d] = scores.map(|score| (score - max).exp());
+    let sum = a + b + c + d;
+    (a / sum, b / sum, c / sum, d / sum)
+}
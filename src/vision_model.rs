@@ -1,58 +1,249 @@
+use std::{path::Path, sync::Mutex};
+
 use image::Rgb32FImage;
-use tch::{Tensor, nn};
+use tch::{
+    Device, Kind, TchError, Tensor,
+    nn::{self, Module},
+};
+
+#[cfg(feature = "onnx")]
+use crate::onnx::{Attribute, OnnxModel, OnnxTensor};
+use crate::{
+    STONE_SIZE,
+    model_file::{Architecture, ModelFormat},
+    recognizer::{BoardRecognizer, VisionModelOutput, pick_intersections},
+};
+
+/// A network that can be used as a vision model.
+pub trait VisionNetwork: Module {
+    /// Adds the network to an ONNX graph, returning the name of its output.
+    #[cfg(feature = "onnx")]
+    fn export_onnx(&self, graph: &mut OnnxModel, input: &str) -> String;
+}
+
+/// A convolutional layer, along with the settings needed to export it.
+#[derive(Debug)]
+struct Conv {
+    layer: nn::Conv2D,
+    stride: i64,
+    padding: i64,
+}
 
-use crate::STONE_SIZE;
+impl Conv {
+    fn new(p: &nn::Path, inputs: i64, outputs: i64, size: i64, stride: i64, padding: i64) -> Self {
+        let config = nn::ConvConfig {
+            stride,
+            padding,
+            ..Default::default()
+        };
+        Self {
+            layer: nn::conv2d(p, inputs, outputs, size, config),
+            stride,
+            padding,
+        }
+    }
+
+    #[cfg(feature = "onnx")]
+    fn export_onnx(&self, graph: &mut OnnxModel, input: &str) -> String {
+        let weight = graph.add_weight(to_onnx_tensor(&self.layer.ws));
+        let bias = graph.add_weight(to_onnx_tensor(self.layer.bs.as_ref().unwrap()));
+        graph.add_node(
+            "Conv",
+            &[input, &weight, &bias],
+            vec![
+                ("strides", Attribute::Ints(vec![self.stride; 2])),
+                ("pads", Attribute::Ints(vec![self.padding; 4])),
+            ],
+        )
+    }
+}
+
+/// Adds a fully connected layer to an ONNX graph, returning the name of its output.
+#[cfg(feature = "onnx")]
+fn export_linear(graph: &mut OnnxModel, input: &str, linear: &nn::Linear) -> String {
+    let weight = graph.add_weight(to_onnx_tensor(&linear.ws));
+    let bias = graph.add_weight(to_onnx_tensor(linear.bs.as_ref().unwrap()));
+    graph.add_node(
+        "Gemm",
+        &[input, &weight, &bias],
+        vec![("transB", Attribute::Int(1))],
+    )
+}
+
+/// Copies the values of a tensor for exporting to ONNX.
+#[cfg(feature = "onnx")]
+fn to_onnx_tensor(tensor: &Tensor) -> OnnxTensor {
+    let shape = tensor.size().iter().map(|&dim| dim as usize).collect();
+    let data = Vec::<f32>::try_from(tensor.to_device(Device::Cpu).flatten(0, -1)).unwrap();
+    OnnxTensor::new(shape, data).unwrap()
+}
 
 /// ### Network architecture:
 ///
-/// - Six input planes, three for R, G, B of the input image and three for R, G, B of the reference image.
+/// - Three input planes for R, G, B of the input image, usually followed by three for R, G, B of the reference image,
+///   and optionally by three for R, G, B of the display as it is projected onto the board.
 /// - Two convolutional layers producing an output with two planes.
 /// - Two fully connected layers producing four classification output values for
 ///   no stone, black stone, white stone, and obscured.
 #[derive(Debug)]
 pub struct VisionModel {
-    conv: nn::Sequential,
-    fc: nn::Sequential,
+    conv1: Conv,
+    conv2: Conv,
+    fc1: nn::Linear,
+    fc2: nn::Linear,
 }
 
 const HIDDEN_PLANES: i64 = 8;
 const INTERMEDIATE_PLANES: i64 = 2;
 const HIDDEN_NODES: i64 = 64;
+const INTERMEDIATE_SIZE: i64 = INTERMEDIATE_PLANES * ((STONE_SIZE - 4) * (STONE_SIZE - 4)) as i64;
 
 impl VisionModel {
-    pub fn new(p: nn::Path) -> Self {
+    pub fn new(p: nn::Path, input_planes: i64) -> Self {
+        Self {
+            conv1: Conv::new(&p, input_planes, HIDDEN_PLANES, 3, 1, 0),
+            conv2: Conv::new(&p, HIDDEN_PLANES, INTERMEDIATE_PLANES, 3, 1, 0),
+            fc1: nn::linear(&p, INTERMEDIATE_SIZE, HIDDEN_NODES, Default::default()),
+            fc2: nn::linear(&p, HIDDEN_NODES, 4, Default::default()),
+        }
+    }
+}
+
+impl nn::Module for VisionModel {
+    fn forward(&self, xs: &Tensor) -> Tensor {
+        xs.apply(&self.conv1.layer)
+            .relu()
+            .apply(&self.conv2.layer)
+            .relu()
+            .view([-1, INTERMEDIATE_SIZE])
+            .apply(&self.fc1)
+            .relu()
+            .apply(&self.fc2)
+    }
+}
+
+impl VisionNetwork for VisionModel {
+    #[cfg(feature = "onnx")]
+    fn export_onnx(&self, graph: &mut OnnxModel, input: &str) -> String {
+        let xs = self.conv1.export_onnx(graph, input);
+        let xs = graph.add_node("Relu", &[&xs], vec![]);
+        let xs = self.conv2.export_onnx(graph, &xs);
+        let xs = graph.add_node("Relu", &[&xs], vec![]);
+        let shape = graph.add_shape(vec![-1, INTERMEDIATE_SIZE]);
+        let xs = graph.add_node("Reshape", &[&xs, &shape], vec![]);
+        let xs = export_linear(graph, &xs, &self.fc1);
+        let xs = graph.add_node("Relu", &[&xs], vec![]);
+        export_linear(graph, &xs, &self.fc2)
+    }
+}
+
+/// ### Network architecture:
+///
+/// - Three input planes covering the whole board for R, G, B of the input image, usually followed by three for R, G, B
+///   of the reference image, and optionally by three for R, G, B of the display as it is projected onto the board.
+/// - Two convolutional layers working on the pixels around each point.
+/// - A convolutional layer with the size and stride of a stone, reducing the image to one point per intersection.
+/// - Two convolutional layers combining each intersection with its neighbours,
+///   so that the shape of a hand or the shadows of nearby stones can be taken into account.
+/// - A final convolutional layer producing the four classification output values for each intersection.
+///
+/// The output has a row of classification values for each intersection, in row-major order for each board.
+#[derive(Debug)]
+pub struct BoardVisionModel {
+    layers: Vec<Conv>,
+}
+
+const BOARD_PIXEL_PLANES: i64 = 16;
+const BOARD_INTERSECTION_PLANES: i64 = 32;
+
+impl BoardVisionModel {
+    pub fn new(p: nn::Path, input_planes: i64) -> Self {
+        let stone = STONE_SIZE as i64;
         Self {
-            conv: nn::seq()
-                .add(nn::conv2d(&p, 6, HIDDEN_PLANES, 3, Default::default()))
-                .add_fn(|xs| xs.relu())
-                .add(nn::conv2d(
+            layers: vec![
+                Conv::new(&p, input_planes, BOARD_PIXEL_PLANES, 3, 1, 1),
+                Conv::new(&p, BOARD_PIXEL_PLANES, BOARD_PIXEL_PLANES, 3, 1, 1),
+                Conv::new(
+                    &p,
+                    BOARD_PIXEL_PLANES,
+                    BOARD_INTERSECTION_PLANES,
+                    stone,
+                    stone,
+                    0,
+                ),
+                Conv::new(
                     &p,
-                    HIDDEN_PLANES,
-                    INTERMEDIATE_PLANES,
+                    BOARD_INTERSECTION_PLANES,
+                    BOARD_INTERSECTION_PLANES,
                     3,
-                    Default::default(),
-                ))
-                .add_fn(|xs| xs.relu()),
-            fc: nn::seq()
-                .add(nn::linear(
+                    1,
+                    1,
+                ),
+                Conv::new(
                     &p,
-                    INTERMEDIATE_PLANES * ((STONE_SIZE - 4) * (STONE_SIZE - 4)) as i64,
-                    HIDDEN_NODES,
-                    Default::default(),
-                ))
-                .add_fn(|xs| xs.relu())
-                .add(nn::linear(&p, HIDDEN_NODES, 4, Default::default())),
+                    BOARD_INTERSECTION_PLANES,
+                    BOARD_INTERSECTION_PLANES,
+                    3,
+                    1,
+                    1,
+                ),
+                Conv::new(&p, BOARD_INTERSECTION_PLANES, 4, 1, 1, 0),
+            ],
         }
     }
 }
 
-impl nn::Module for VisionModel {
+impl nn::Module for BoardVisionModel {
     fn forward(&self, xs: &Tensor) -> Tensor {
-        let intermediate = self.conv.forward(xs).view([
-            -1,
-            INTERMEDIATE_PLANES * ((STONE_SIZE - 4) * (STONE_SIZE - 4)) as i64,
-        ]);
-        self.fc.forward(&intermediate)
+        let mut xs = xs.shallow_clone();
+        for (i, conv) in self.layers.iter().enumerate() {
+            xs = xs.apply(&conv.layer);
+            if i + 1 < self.layers.len() {
+                xs = xs.relu();
+            }
+        }
+        // Move the classification values last, then give each intersection its own row
+        xs.permute([0, 2, 3, 1]).reshape([-1, 4])
+    }
+}
+
+impl VisionNetwork for BoardVisionModel {
+    #[cfg(feature = "onnx")]
+    fn export_onnx(&self, graph: &mut OnnxModel, input: &str) -> String {
+        let mut xs = input.to_string();
+        for (i, conv) in self.layers.iter().enumerate() {
+            xs = conv.export_onnx(graph, &xs);
+            if i + 1 < self.layers.len() {
+                xs = graph.add_node("Relu", &[&xs], vec![]);
+            }
+        }
+        let xs = graph.add_node(
+            "Transpose",
+            &[&xs],
+            vec![("perm", Attribute::Ints(vec![0, 2, 3, 1]))],
+        );
+        let shape = graph.add_shape(vec![-1, 4]);
+        graph.add_node("Reshape", &[&xs, &shape], vec![])
+    }
+}
+
+impl ModelFormat {
+    /// Creates a model with this architecture and inputs.
+    pub fn build(self, p: nn::Path) -> Box<dyn VisionNetwork> {
+        let input_planes = self.input_planes.count() as i64;
+        match self.architecture {
+            Architecture::Crop => Box::new(VisionModel::new(p, input_planes)),
+            Architecture::Board => Box::new(BoardVisionModel::new(p, input_planes)),
+        }
+    }
+
+    /// Arranges the input planes of the whole board into a batch for the model.
+    /// The model's output has a row of classification values for each intersection in row-major order.
+    pub fn batch_input(self, board: &Tensor) -> Tensor {
+        match self.architecture {
+            Architecture::Crop => crop_intersections(board),
+            Architecture::Board => board.unsqueeze(0),
+        }
     }
 }
 
@@ -61,20 +252,247 @@ pub const LBL_BLACK: u8 = 1;
 pub const LBL_WHITE: u8 = 2;
 pub const LBL_OBSCURED: u8 = 3;
 
-/// Constructs an input tensor from the given location in the image.
-pub fn read_tensor(image: &Rgb32FImage, reference: &Rgb32FImage, x0: u32, y0: u32) -> Tensor {
-    let mut data = [0.0; (6 * STONE_SIZE * STONE_SIZE) as usize];
-    for y in 0..STONE_SIZE {
-        for x in 0..STONE_SIZE {
-            let pixel = image.get_pixel(x0 + x, y0 + y);
-            let ref_pixel = reference.get_pixel(x0 + x, y0 + y);
-            data[(y * STONE_SIZE + x) as usize] = pixel[0];
-            data[(STONE_SIZE * STONE_SIZE + y * STONE_SIZE + x) as usize] = pixel[1];
-            data[(2 * STONE_SIZE * STONE_SIZE + y * STONE_SIZE + x) as usize] = pixel[2];
-            data[(3 * STONE_SIZE * STONE_SIZE + y * STONE_SIZE + x) as usize] = ref_pixel[0];
-            data[(4 * STONE_SIZE * STONE_SIZE + y * STONE_SIZE + x) as usize] = ref_pixel[1];
-            data[(5 * STONE_SIZE * STONE_SIZE + y * STONE_SIZE + x) as usize] = ref_pixel[2];
+/// Converts an image to a tensor with three planes for R, G, B.
+fn image_planes(image: &Rgb32FImage) -> Tensor {
+    Tensor::from_slice(image.as_raw())
+        .view([image.height() as i64, image.width() as i64, 3])
+        .permute([2, 0, 1])
+}
+
+/// Constructs an input tensor from the whole images, with three planes for R, G, B of each image.
+pub fn read_board_tensor(images: &[&Rgb32FImage]) -> Tensor {
+    let planes: Vec<_> = images.iter().map(|image| image_planes(image)).collect();
+    Tensor::cat(&planes, 0)
+}
+
+/// Cuts the input planes of the whole board into a batch of stone-sized crops,
+/// one for each intersection in row-major order.
+pub fn crop_intersections(board: &Tensor) -> Tensor {
+    let stone = STONE_SIZE as i64;
+    let planes = board.size()[0];
+    // Split the rows and then the columns into stone-sized blocks, giving [planes, rows, columns, stone, stone]
+    board
+        .unfold(1, stone, stone)
+        .unfold(2, stone, stone)
+        .permute([1, 2, 0, 3, 4])
+        .reshape([-1, planes, stone, stone])
+}
+
+/// The input planes of an image that rarely changes, kept on the device between frames.
+#[derive(Default)]
+struct CachedPlanes {
+    source: Vec<f32>,
+    planes: Option<Tensor>,
+}
+
+impl CachedPlanes {
+    /// Returns the planes of the image, only uploading them again if the image has changed.
+    fn get(&mut self, image: &Rgb32FImage, device: Device) -> Tensor {
+        if let Some(planes) = &self.planes
+            && self.source == **image
+        {
+            return planes.shallow_clone();
         }
+        let planes = image_planes(image).to(device).contiguous();
+        self.source = image.as_raw().clone();
+        self.planes = Some(planes.shallow_clone());
+        planes
+    }
+}
+
+/// Runs a vision model loaded from a file as a [`BoardRecognizer`].
+pub struct VisionModelRecognizer {
+    model: Box<dyn VisionNetwork>,
+    format: ModelFormat,
+    device: Device,
+    vs: nn::VarStore,
+    /// The planes of every input image after the board image, such as the reference image.
+    cached_planes: Mutex<Vec<CachedPlanes>>,
+}
+
+impl VisionModelRecognizer {
+    /// Loads the model's weights from a file, checking that they fit the network architecture.
+    pub fn load(path: &Path, format: ModelFormat) -> Result<Self, TchError> {
+        let device = Device::cuda_if_available();
+        let mut vs = nn::VarStore::new(device);
+        let model = format.build(vs.root());
+        vs.load(path)?;
+
+        // Make sure the model produces an output for each of the four classes
+        let input = Tensor::zeros(
+            [
+                1,
+                format.input_planes.count() as i64,
+                STONE_SIZE as i64,
+                STONE_SIZE as i64,
+            ],
+            (Kind::Float, device),
+        );
+        let output = tch::no_grad(|| model.forward(&input));
+        if output.size() != [1, 4] {
+            return Err(TchError::Shape(format!(
+                "Expected an output of shape [1, 4], but got {:?}.",
+                output.size()
+            )));
+        }
+
+        let cached_images = format.input_planes.count() / 3 - 1;
+        Ok(Self {
+            model,
+            format,
+            device,
+            vs,
+            cached_planes: Mutex::new((0..cached_images).map(|_| Default::default()).collect()),
+        })
+    }
+
+    /// Returns the number of trained parameters in the model.
+    pub fn parameters(&self) -> i64 {
+        self.vs
+            .trainable_variables()
+            .iter()
+            .map(|variable| variable.numel() as i64)
+            .sum()
+    }
+
+    /// Constructs the input planes of the whole board on the model's device.
+    fn read_planes(
+        &self,
+        image: &Rgb32FImage,
+        reference: &Rgb32FImage,
+        display: &Rgb32FImage,
+    ) -> Tensor {
+        let images = self.format.input_images(image, reference, display);
+
+        // Only the board image changes every frame, so the other images are uploaded when they change
+        let mut planes = vec![image_planes(images[0]).to(self.device)];
+        let mut cached_planes = self.cached_planes.lock().unwrap();
+        for (cached, image) in cached_planes.iter_mut().zip(&images[1..]) {
+            planes.push(cached.get(image, self.device));
+        }
+        Tensor::cat(&planes, 0)
+    }
+
+    /// Runs the model on a batch of inputs, returning the probabilities of each class for each output row.
+    fn run(&self, input: &Tensor) -> Vec<Vec<f32>> {
+        tch::no_grad(|| self.model.forward(input))
+            .softmax(1, Kind::Float)
+            .try_into()
+            .unwrap()
+    }
+}
+
+impl BoardRecognizer for VisionModelRecognizer {
+    fn recognize(
+        &self,
+        image: &Rgb32FImage,
+        reference: &Rgb32FImage,
+        display: &Rgb32FImage,
+    ) -> Result<Vec<Vec<VisionModelOutput>>, String> {
+        let width = reference.width() / STONE_SIZE;
+        let height = reference.height() / STONE_SIZE;
+        let input = self
+            .format
+            .batch_input(&self.read_planes(image, reference, display));
+        let output = self.run(&input);
+
+        let mut result = Vec::with_capacity(height as usize);
+        for y in 0..height {
+            result.push(Vec::with_capacity(width as usize));
+            for x in 0..width {
+                let index = (y * width + x) as usize;
+                result[y as usize].push((
+                    output[index][0],
+                    output[index][1],
+                    output[index][2],
+                    output[index][3],
+                ));
+            }
+        }
+        Ok(result)
+    }
+
+    fn recognize_intersections(
+        &self,
+        image: &Rgb32FImage,
+        reference: &Rgb32FImage,
+        display: &Rgb32FImage,
+        intersections: &[(u32, u32)],
+    ) -> Result<Vec<VisionModelOutput>, String> {
+        // Whole-board models need the surrounding intersections, so only crops can be classified separately
+        if self.format.architecture != Architecture::Crop {
+            return Ok(pick_intersections(
+                &self.recognize(image, reference, display)?,
+                intersections,
+            ));
+        }
+
+        let width = reference.width() / STONE_SIZE;
+        let indexes: Vec<i64> = intersections
+            .iter()
+            .map(|&(x, y)| (y * width + x) as i64)
+            .collect();
+        let crops = crop_intersections(&self.read_planes(image, reference, display));
+        let input = crops.index_select(0, &Tensor::from_slice(&indexes).to(self.device));
+        Ok(self
+            .run(&input)
+            .into_iter()
+            .map(|output| (output[0], output[1], output[2], output[3]))
+            .collect())
+    }
+
+    fn recognizes_intersections_separately(&self) -> bool {
+        self.format.architecture == Architecture::Crop
+    }
+
+    fn needs_reference(&self) -> bool {
+        self.format.input_planes.reference
+    }
+}
+
+#[cfg(all(test, feature = "onnx"))]
+mod tests {
+    use super::*;
+    use crate::model_file::InputPlanes;
+
+    /// Checks that a model exported to ONNX and loaded again gives the same output as the LibTorch model.
+    fn check_export(architecture: Architecture, size: i64) {
+        let format = ModelFormat {
+            architecture,
+            input_planes: InputPlanes {
+                reference: true,
+                display: true,
+            },
+        };
+        let planes = format.input_planes.count();
+        let vs = nn::VarStore::new(Device::Cpu);
+        let model = format.build(vs.root());
+        let mut graph = OnnxModel::new("input");
+        model.export_onnx(&mut graph, "input");
+        let graph = OnnxModel::from_bytes(&graph.to_bytes()).unwrap();
+
+        let input = Tensor::rand([2, planes as i64, size, size], (Kind::Float, Device::Cpu));
+        let expected =
+            Vec::<f32>::try_from(tch::no_grad(|| model.forward(&input)).flatten(0, -1)).unwrap();
+        let output = graph.run(to_onnx_tensor(&input)).unwrap();
+        assert_eq!(output.data.len(), expected.len());
+        for (actual, expected) in output.data.iter().zip(expected) {
+            assert!(
+                (actual - expected).abs() < 1e-4,
+                "{} != {}",
+                actual,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn export_crop_model() {
+        check_export(Architecture::Crop, STONE_SIZE as i64);
+    }
+
+    #[test]
+    fn export_board_model() {
+        check_export(Architecture::Board, 3 * STONE_SIZE as i64);
     }
-    Tensor::from_slice(&data).view([6, STONE_SIZE as i64, STONE_SIZE as i64])
 }